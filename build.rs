@@ -0,0 +1,190 @@
+//! Embed zone images into hvisor with the `embed_images` feature.
+//!
+//! The manifest, `EMBED_MANIFEST` or `images/<arch>/embed.manifest`, lists
+//! the zones started at boot. Each `zone <id>` line begins a zone, the lines
+//! after it describe it:
+//!
+//! ```text
+//! zone 0
+//! cpus 0 1
+//! memory ram 0x50000000 0x50000000 0x80000000
+//! memory io 0x9000000 0x9000000 0x1000
+//! irqs 33 64 77 79
+//! kernel kernel/Image 0xa0400000
+//! dtb devicetree/linux1.dtb 0xa0000000
+//! entry 0xa0400000
+//! ```
+//!
+//! `memory` takes the type, physical start, virtual start and size of a
+//! region, followed by any of the flags `readonly`, `noexec`, `device` and
+//! `uncached`, the last only on `ram` regions. `kernel` and `dtb` take a path relative to the manifest and the
+//! physical load address. `entry` defaults to the kernel address. Zone 0 is
+//! the root zone and comes alone, other zones partition the board.
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+struct Zone {
+    id: u32,
+    cpus: u64,
+    regions: Vec<(u32, u32, u64, u64, u64)>,
+    irqs: Vec<u32>,
+    kernel: Option<(PathBuf, u64)>,
+    dtb: Option<(PathBuf, u64)>,
+    entry: Option<u64>,
+}
+
+fn parse_num(s: &str) -> u64 {
+    let ret = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    ret.unwrap_or_else(|_| panic!("bad number in embed manifest: {}", s))
+}
+
+fn parse_manifest(path: &Path) -> Vec<Zone> {
+    let text = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("can't read embed manifest {}: {}", path.display(), e));
+    let dir = path.parent().unwrap();
+    let mut zones: Vec<Zone> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        let mut words = line.split_whitespace();
+        let key = match words.next() {
+            Some(key) => key,
+            None => continue,
+        };
+        let args: Vec<&str> = words.collect();
+        if key == "zone" {
+            zones.push(Zone {
+                id: parse_num(args[0]) as _,
+                ..Default::default()
+            });
+            continue;
+        }
+        let zone = zones
+            .last_mut()
+            .unwrap_or_else(|| panic!("embed manifest line {}: {} before zone", i + 1, key));
+        match (key, args.as_slice()) {
+            ("cpus", cpus) => zone.cpus = cpus.iter().fold(0, |acc, c| acc | 1 << parse_num(c)),
+            ("memory", [mem_type, phys, virt, size, flags @ ..]) => {
+                let mem_type = match *mem_type {
+                    "ram" => 0,
+                    "io" => 1,
+                    "virtio" => 2,
+                    _ => panic!("embed manifest line {}: bad memory type", i + 1),
+                };
+                let flags = flags.iter().fold(0, |acc, flag| {
+                    acc | match *flag {
+                        "readonly" => 1 << 0,
+                        "noexec" => 1 << 1,
+                        "device" => 1 << 2,
+                        "uncached" => 1 << 3,
+                        _ => panic!("embed manifest line {}: bad memory flag", i + 1),
+                    }
+                });
+                if mem_type != 0 && flags & 1 << 3 != 0 {
+                    panic!("embed manifest line {}: uncached is only for ram", i + 1);
+                }
+                zone.regions.push((
+                    mem_type,
+                    flags,
+                    parse_num(phys),
+                    parse_num(virt),
+                    parse_num(size),
+                ));
+            }
+            ("irqs", irqs) => zone
+                .irqs
+                .extend(irqs.iter().map(|irq| parse_num(irq) as u32)),
+            ("kernel", [file, addr]) => zone.kernel = Some((dir.join(file), parse_num(addr))),
+            ("dtb", [file, addr]) => zone.dtb = Some((dir.join(file), parse_num(addr))),
+            ("entry", [entry]) => zone.entry = Some(parse_num(entry)),
+            _ => panic!("embed manifest line {}: can't parse {:?}", i + 1, line),
+        }
+    }
+    if zones.iter().any(|zone| zone.id == 0) && zones.len() != 1 {
+        panic!("embed manifest: the root zone 0 comes alone");
+    }
+    zones
+}
+
+fn image(zone: &Zone, image: &Option<(PathBuf, u64)>, name: &str) -> (PathBuf, u64) {
+    let (path, addr) = image
+        .clone()
+        .unwrap_or_else(|| panic!("embed manifest: zone {} has no {}", zone.id, name));
+    let path = fs::canonicalize(&path)
+        .unwrap_or_else(|e| panic!("embed manifest: {}: {}", path.display(), e));
+    println!("cargo:rerun-if-changed={}", path.display());
+    (path, addr)
+}
+
+fn generate(zones: &[Zone]) -> String {
+    let mut images = String::new();
+    let mut configs = String::new();
+    for zone in zones {
+        let (kernel, kernel_addr) = image(zone, &zone.kernel, "kernel");
+        let (dtb, dtb_addr) = image(zone, &zone.dtb, "dtb");
+        for (path, addr) in [(kernel, kernel_addr), (dtb, dtb_addr)] {
+            writeln!(
+                images,
+                "    EmbeddedImage {{ zone_id: {}, load_paddr: {:#x}, data: include_bytes!({:?}) }},",
+                zone.id, addr, path
+            )
+            .unwrap();
+        }
+        let mut regions = String::new();
+        for (mem_type, flags, phys, virt, size) in &zone.regions {
+            write!(
+                regions,
+                "HvConfigMemoryRegion {{ mem_type: {}, flags: {:#x}, physical_start: {:#x}, \
+                 virtual_start: {:#x}, size: {:#x} }}, ",
+                mem_type, flags, phys, virt, size
+            )
+            .unwrap();
+        }
+        writeln!(
+            configs,
+            "    StaticZone {{ zone_id: {}, cpus: {:#x}, memory_regions: &[{}], irqs: &{:?}, \
+             entry: {:#x}, kernel_addr: {:#x}, dtb_addr: {:#x}, arch: ROOT_ARCH_ZONE_CONFIG }},",
+            zone.id,
+            zone.cpus,
+            regions,
+            zone.irqs,
+            zone.entry.unwrap_or(kernel_addr),
+            kernel_addr,
+            dtb_addr
+        )
+        .unwrap();
+    }
+    format!(
+        "pub const EMBEDDED_IMAGES: &[EmbeddedImage] = &[\n{}];\n\n\
+         pub const EMBEDDED_ZONES: &[StaticZone] = &[\n{}];\n",
+        images, configs
+    )
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=EMBED_MANIFEST");
+    if env::var_os("CARGO_FEATURE_EMBED_IMAGES").is_none() {
+        return;
+    }
+    let manifest = match env::var_os("EMBED_MANIFEST").filter(|path| !path.is_empty()) {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+            Path::new("images").join(arch).join("embed.manifest")
+        }
+    };
+    println!("cargo:rerun-if-changed={}", manifest.display());
+    let zones = parse_manifest(&manifest);
+    if zones.is_empty() {
+        panic!("embed manifest {}: no zones", manifest.display());
+    }
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("embedded_zones.rs");
+    fs::write(out, generate(&zones)).unwrap();
+}
@@ -0,0 +1,28 @@
+//! Data cache maintenance.
+
+use super::sysreg::read_sysreg;
+
+/// Smallest data cache line size in bytes, from CTR_EL0.DminLine.
+fn dcache_line_size() -> usize {
+    4 << ((read_sysreg!(ctr_el0) >> 16) & 0xf)
+}
+
+/// Clean and invalidate `start..start + size` to the point of coherency.
+pub fn dcache_clean_invalidate_range(start: usize, size: usize) {
+    let line_size = dcache_line_size();
+    let mut addr = start & !(line_size - 1);
+    while addr < start + size {
+        unsafe { core::arch::asm!("dc civac, {}", in(reg) addr) };
+        addr += line_size;
+    }
+    unsafe { core::arch::asm!("dsb sy") };
+}
+
+/// Invalidate all instruction caches to the point of unification.
+pub fn icache_invalidate_all() {
+    unsafe {
+        core::arch::asm!("ic ialluis");
+        core::arch::asm!("dsb ish");
+        core::arch::asm!("isb");
+    }
+}
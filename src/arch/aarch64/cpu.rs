@@ -1,11 +1,13 @@
 use crate::{
     arch::{mm::new_s2_memory_set, sysreg::write_sysreg},
     consts::{PAGE_SIZE, PER_CPU_ARRAY_PTR, PER_CPU_SIZE},
+    device::irqchip::gicv3::gicv3_clear_pending_irqs,
     memory::{
         addr::PHYS_VIRT_OFFSET, mm::PARKING_MEMORY_SET, GuestPhysAddr, HostPhysAddr, MemFlags,
         MemoryRegion, VirtAddr, PARKING_INST_PAGE,
     },
     percpu::this_cpu_data,
+    sched,
 };
 use aarch64_cpu::registers::{
     Readable, Writeable, ELR_EL2, HCR_EL2, MPIDR_EL1, SCTLR_EL1, SPSR_EL2, VTCR_EL2,
@@ -61,6 +63,7 @@ impl ArchCpu {
         regs.clear();
         regs.usr[0] = dtb as _; // dtb addr
         self.reset_vm_regs();
+        gicv3_clear_pending_irqs();
         self.activate_vmm();
     }
 
@@ -94,7 +97,7 @@ impl ArchCpu {
         PER_CPU_ARRAY_PTR as VirtAddr + (self.cpuid + 1) as usize * PER_CPU_SIZE
     }
 
-    fn guest_reg(&self) -> &mut GeneralRegisters {
+    pub fn guest_reg(&self) -> &mut GeneralRegisters {
         unsafe { &mut *((self.stack_top() - 32 * 8) as *mut GeneralRegisters) }
     }
 
@@ -147,7 +150,13 @@ impl ArchCpu {
         assert!(this_cpu_id() == self.cpuid);
         this_cpu_data().activate_gpm();
         self.reset(this_cpu_data().cpu_on_entry, this_cpu_data().dtb_ipa);
+        // A vCPU restored from a checkpoint continues where it was saved.
+        if let Some(ctx) = sched::take_saved_context(self.cpuid) {
+            ctx.restore(self.guest_reg());
+        }
         self.psci_on = true;
+        // Outside its window the vCPU waits for it.
+        sched::reschedule(false);
         unsafe {
             vmreturn(self.guest_reg() as *mut _ as usize);
         }
@@ -160,6 +169,17 @@ impl ArchCpu {
         self.psci_on = false;
         drop(_lock);
 
+        // Hand the cpu over to a vCPU of another zone sharing it.
+        if sched::reschedule(false) {
+            unsafe {
+                vmreturn(self.guest_reg() as *mut _ as usize);
+            }
+        }
+        self.park();
+    }
+
+    /// Run the wfi loop in place of a guest until the next event.
+    pub fn park(&mut self) -> ! {
         // reset current cpu -> pc = 0x0 (wfi)
         PARKING_MEMORY_SET.call_once(|| {
             let parking_code: [u8; 8] = [0x7f, 0x20, 0x03, 0xd5, 0xff, 0xff, 0xff, 0x17]; // 1: wfi; b 1b
@@ -172,7 +192,7 @@ impl ArchCpu {
                 0 as GuestPhysAddr,
                 unsafe { &PARKING_INST_PAGE as *const _ as HostPhysAddr - PHYS_VIRT_OFFSET },
                 PAGE_SIZE,
-                MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+                MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE | MemFlags::IO,
             ))
             .unwrap();
             gpm
@@ -1,3 +1,4 @@
+pub mod cache;
 pub mod cpu;
 pub mod entry;
 pub mod ipi;
@@ -6,7 +7,9 @@ pub mod paging;
 pub mod s1pt;
 pub mod s2pt;
 pub mod sysreg;
+pub mod timer;
 pub mod trap;
+pub mod vcpu;
 pub mod zone;
 
 pub use s1pt::Stage1PageTable;
@@ -32,6 +32,8 @@ bitflags::bitflags! {
         const SHAREABLE =   1 << 9;
         /// The Access flag.
         const AF =          1 << 10;
+        /// Execute-never at EL0 and EL1.
+        const XN =          1 << 54;
     }
 }
 
@@ -40,6 +42,7 @@ numeric_enum! {
     #[derive(Debug, Clone, Copy, Eq, PartialEq)]
     enum MemType {
         Device = 1,
+        NormalNonCacheable = 5,
         Normal = 15,
     }
 }
@@ -49,7 +52,7 @@ impl DescriptorAttr {
 
     const fn from_mem_type(mem_type: MemType) -> Self {
         let mut bits = (mem_type as u64) << 2;
-        if matches!(mem_type, MemType::Normal) {
+        if !matches!(mem_type, MemType::Device) {
             bits |= Self::INNER.bits() | Self::SHAREABLE.bits();
         }
         Self::from_bits_truncate(bits)
@@ -59,6 +62,7 @@ impl DescriptorAttr {
         let idx = (self.bits() & Self::ATTR_INDEX_MASK) >> 2;
         match idx {
             1 => MemType::Device,
+            5 => MemType::NormalNonCacheable,
             15 => MemType::Normal,
             _ => panic!("Invalid memory attribute index"),
         }
@@ -69,6 +73,16 @@ impl MemType {
     fn empty() -> Self {
         Self::try_from(0).unwrap()
     }
+
+    fn of(flags: MemFlags) -> Self {
+        if flags.contains(MemFlags::NO_CACHE) {
+            Self::NormalNonCacheable
+        } else if flags.intersects(MemFlags::IO | MemFlags::DEVICE) {
+            Self::Device
+        } else {
+            Self::Normal
+        }
+    }
 }
 
 impl From<DescriptorAttr> for MemFlags {
@@ -80,8 +94,13 @@ impl From<DescriptorAttr> for MemFlags {
         if attr.contains(DescriptorAttr::S2AP_W) {
             flags |= Self::WRITE;
         }
-        if attr.mem_type() == MemType::Device {
-            flags |= Self::IO;
+        if !attr.contains(DescriptorAttr::XN) {
+            flags |= Self::EXECUTE;
+        }
+        match attr.mem_type() {
+            MemType::Device => flags |= Self::IO,
+            MemType::NormalNonCacheable => flags |= Self::NO_CACHE,
+            MemType::Normal => {}
         }
         flags
     }
@@ -89,11 +108,7 @@ impl From<DescriptorAttr> for MemFlags {
 
 impl From<MemFlags> for DescriptorAttr {
     fn from(flags: MemFlags) -> Self {
-        let mut attr = if flags.contains(MemFlags::IO) {
-            Self::from_mem_type(MemType::Device)
-        } else {
-            Self::from_mem_type(MemType::Normal)
-        };
+        let mut attr = Self::from_mem_type(MemType::of(flags));
         attr |= Self::VALID | Self::AF;
         if flags.contains(MemFlags::READ) {
             attr |= Self::S2AP_R;
@@ -101,6 +116,9 @@ impl From<MemFlags> for DescriptorAttr {
         if flags.contains(MemFlags::WRITE) {
             attr |= Self::S2AP_W;
         }
+        if !flags.contains(MemFlags::EXECUTE) {
+            attr |= Self::XN;
+        }
         attr
     }
 }
@@ -144,10 +162,7 @@ impl GenericPTE for PageTableEntry {
     }
 
     fn set_flags(&mut self, flags: MemFlags, is_huge: bool) {
-        let mut mem_type: MemType = MemType::Normal;
-        if flags.contains(MemFlags::IO) {
-            mem_type = MemType::Device;
-        }
+        let mem_type = MemType::of(flags);
         let mut flags: DescriptorAttr = flags.into();
         if !is_huge {
             flags |= DescriptorAttr::NON_BLOCK;
@@ -208,7 +223,13 @@ impl PagingInstr for S2PTInstr {
     }
 
     fn flush(_vaddr: Option<usize>) {
-        // do nothing
+        // All zones share VMID 0, so drop every stage 1&2 entry of it.
+        unsafe {
+            core::arch::asm!("dsb ishst");
+            core::arch::asm!("tlbi vmalls12e1is");
+            core::arch::asm!("dsb ish");
+            core::arch::asm!("isb");
+        }
     }
 }
 
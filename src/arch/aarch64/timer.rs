@@ -0,0 +1,97 @@
+//! EL2 physical timer (CNTHP), owned by the hypervisor.
+//!
+//! Each cpu's timer is shared by several users, it is programmed with the
+//! earliest of their deadlines.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use aarch64_cpu::registers::{Readable, CNTFRQ_EL0, CNTPCT_EL0};
+
+use super::cpu::this_cpu_id;
+use super::sysreg::write_sysreg;
+use crate::consts::MAX_CPU_NUM;
+use crate::{sched, watchdog};
+
+/// PPI of the EL2 physical timer.
+pub const HYP_TIMER_IRQ: usize = 26;
+
+pub const HYP_TIMER_WATCHDOG: usize = 0;
+pub const HYP_TIMER_SCHED: usize = 1;
+const HYP_TIMER_NUM: usize = 2;
+
+const UNSET: AtomicU64 = AtomicU64::new(0);
+const CPU_UNSET: [AtomicU64; HYP_TIMER_NUM] = [UNSET; HYP_TIMER_NUM];
+/// Deadlines of the timer users of each cpu in counter ticks, 0 if unset.
+static DEADLINES: [[AtomicU64; HYP_TIMER_NUM]; MAX_CPU_NUM] = [CPU_UNSET; MAX_CPU_NUM];
+
+pub fn current_ticks() -> u64 {
+    CNTPCT_EL0.get()
+}
+
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    CNTFRQ_EL0.get() * ms / 1000
+}
+
+pub fn ticks_to_us(ticks: u64) -> u64 {
+    ticks * 1_000_000 / CNTFRQ_EL0.get()
+}
+
+fn reprogram(deadlines: &[AtomicU64; HYP_TIMER_NUM]) {
+    match deadlines
+        .iter()
+        .map(|deadline| deadline.load(Ordering::Relaxed))
+        .filter(|&deadline| deadline != 0)
+        .min()
+    {
+        Some(deadline) => {
+            write_sysreg!(CNTHP_CVAL_EL2, deadline);
+            write_sysreg!(CNTHP_CTL_EL2, 1); // ENABLE
+        }
+        None => write_sysreg!(CNTHP_CTL_EL2, 0),
+    }
+}
+
+/// Call the handler of `timer` on this cpu in `ms` milliseconds.
+pub fn hyp_timer_set(timer: usize, ms: u64) {
+    hyp_timer_set_deadline(timer, current_ticks() + ms_to_ticks(ms));
+}
+
+/// Call the handler of `timer` on this cpu once the counter reaches `ticks`.
+pub fn hyp_timer_set_deadline(timer: usize, ticks: u64) {
+    let deadlines = &DEADLINES[this_cpu_id()];
+    deadlines[timer].store(ticks, Ordering::Relaxed);
+    reprogram(deadlines);
+}
+
+pub fn hyp_timer_cancel(timer: usize) {
+    let deadlines = &DEADLINES[this_cpu_id()];
+    deadlines[timer].store(0, Ordering::Relaxed);
+    reprogram(deadlines);
+}
+
+/// Deadline of `timer` on this cpu in counter ticks, None if unset.
+pub fn hyp_timer_deadline(timer: usize) -> Option<u64> {
+    match DEADLINES[this_cpu_id()][timer].load(Ordering::Relaxed) {
+        0 => None,
+        deadline => Some(deadline),
+    }
+}
+
+/// Handle `HYP_TIMER_IRQ`: run the handlers whose deadline has passed.
+pub fn handle_hyp_timer() {
+    let deadlines = &DEADLINES[this_cpu_id()];
+    let now = current_ticks();
+    for (timer, deadline) in deadlines.iter().enumerate() {
+        let value = deadline.load(Ordering::Relaxed);
+        if value == 0 || value > now {
+            continue;
+        }
+        deadline.store(0, Ordering::Relaxed);
+        match timer {
+            HYP_TIMER_WATCHDOG => watchdog::check(),
+            HYP_TIMER_SCHED => sched::tick(),
+            _ => {}
+        }
+    }
+    reprogram(deadlines);
+}
@@ -7,11 +7,16 @@ use crate::{
         sysreg::{read_sysreg, write_sysreg},
     },
     device::irqchip::gicv3::gicv3_handle_irq_el1,
-    event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_WAKEUP},
+    event::{send_event, IPI_EVENT_SHUTDOWN},
     hypercall::{HyperCall, SGI_IPI_ID},
     memory::{mmio_handle_access, MMIOAccess},
-    percpu::{get_cpu_data, this_cpu_data, this_zone, PerCpu},
-    zone::{is_this_root_zone, remove_zone},
+    percpu::{this_cpu_data, this_zone, PerCpu},
+    sched,
+    stats::{
+        count_el2_ticks, count_exit, STATS_EXIT_DABT, STATS_EXIT_HVC, STATS_EXIT_IRQ,
+        STATS_EXIT_SMC, STATS_EXIT_SYSREG,
+    },
+    zone::{is_this_root_zone, num_zones, remove_zone, this_zone_id},
 };
 
 use super::cpu::GeneralRegisters;
@@ -39,6 +44,7 @@ pub mod SmcType {
 
 const PSCI_VERSION_1_1: u64 = 0x10001;
 const PSCI_TOS_NOT_PRESENT_MP: u64 = 2;
+const PSCI_DENIED: u64 = -3i64 as u64;
 const ARM_SMCCC_VERSION_1_0: u64 = 0x10000;
 
 extern "C" {
@@ -61,6 +67,7 @@ pub mod PsciFnId {
     pub const PSCI_AFFINITY_INFO_32: u64 = 0x84000004;
     pub const PSCI_MIG_INFO_TYPE: u64 = 0x84000006;
     pub const PSCI_SYSTEM_OFF: u64 = 0x84000008;
+    pub const PSCI_SYSTEM_RESET: u64 = 0x84000009;
     pub const PSCI_FEATURES: u64 = 0x8400000a;
 
     pub const PSCI_CPU_SUSPEND_64: u64 = 0xc4000001;
@@ -85,6 +92,7 @@ pub enum TrapReturn {
 pub fn arch_handle_exit(regs: &mut GeneralRegisters) -> ! {
     let mpidr = MPIDR_EL1.get();
     let _cpu_id = mpidr_to_cpuid(mpidr);
+    let exit_ticks = CNTPCT_EL0.get();
     trace!("cpu exit, exit_reson:{:#x?}", regs.exit_reason);
     match regs.exit_reason as u64 {
         ExceptionType::EXIT_REASON_EL1_IRQ => irqchip_handle_irq1(),
@@ -93,11 +101,13 @@ pub fn arch_handle_exit(regs: &mut GeneralRegisters) -> ! {
         ExceptionType::EXIT_REASON_EL2_IRQ => irqchip_handle_irq2(),
         _ => arch_dump_exit(regs.exit_reason),
     }
+    count_el2_ticks(CNTPCT_EL0.get() - exit_ticks);
     unsafe { vmreturn(regs as *const _ as usize) }
 }
 
 fn irqchip_handle_irq1() {
     trace!("irq from el1");
+    count_exit(STATS_EXIT_IRQ);
     gicv3_handle_irq_el1();
 }
 
@@ -116,10 +126,22 @@ fn arch_handle_trap_el1(regs: &mut GeneralRegisters) {
     );
 
     match ESR_EL2.read_as_enum(ESR_EL2::EC) {
-        Some(ESR_EL2::EC::Value::HVC64) => handle_hvc(regs),
-        Some(ESR_EL2::EC::Value::SMC64) => handle_smc(regs),
-        Some(ESR_EL2::EC::Value::TrappedMsrMrs) => handle_sysreg(regs),
-        Some(ESR_EL2::EC::Value::DataAbortLowerEL) => handle_dabt(regs),
+        Some(ESR_EL2::EC::Value::HVC64) => {
+            count_exit(STATS_EXIT_HVC);
+            handle_hvc(regs)
+        }
+        Some(ESR_EL2::EC::Value::SMC64) => {
+            count_exit(STATS_EXIT_SMC);
+            handle_smc(regs)
+        }
+        Some(ESR_EL2::EC::Value::TrappedMsrMrs) => {
+            count_exit(STATS_EXIT_SYSREG);
+            handle_sysreg(regs)
+        }
+        Some(ESR_EL2::EC::Value::DataAbortLowerEL) => {
+            count_exit(STATS_EXIT_DABT);
+            handle_dabt(regs)
+        }
         Some(ESR_EL2::EC::Value::InstrAbortLowerEL) => handle_iabt(regs),
         _ => {
             error!(
@@ -295,28 +317,24 @@ fn psci_emulate_features_info(code: u64) -> u64 {
         | PsciFnId::PSCI_AFFINITY_INFO_32
         | PsciFnId::PSCI_AFFINITY_INFO_64
         | PsciFnId::PSCI_FEATURES
+        | PsciFnId::PSCI_SYSTEM_RESET
         | SMCccFnId::SMCCC_VERSION => 0,
         _ => !0,
     }
 }
 
 fn psci_emulate_cpu_on(regs: &mut GeneralRegisters) -> u64 {
-    // Todo: Check if `cpu` is in the cpuset of current zone
     let cpu = mpidr_to_cpuid(regs.usr[1]);
     info!("psci: try to wake up cpu {}", cpu);
+    if !this_zone().read().owns_cpu(cpu as _) {
+        error!("psci: cpu {} not in this zone", cpu);
+        return u64::MAX - 1; // INVALID_PARAMETERS
+    }
 
-    let target_data = get_cpu_data(cpu as _);
-    let _lock = target_data.ctrl_lock.lock();
-
-    if !target_data.arch_cpu.psci_on {
-        target_data.cpu_on_entry = regs.usr[2] as _;
-        target_data.arch_cpu.psci_on = true;
-        send_event(cpu as _, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
-    } else {
+    if !sched::wake(cpu as _, this_zone_id(), regs.usr[2] as _) {
         error!("psci: cpu {} already on", cpu);
         return u64::MAX - 3;
-    };
-    drop(_lock);
+    }
 
     0
 }
@@ -331,15 +349,16 @@ fn handle_psci_smc(
     match code {
         PsciFnId::PSCI_VERSION => PSCI_VERSION_1_1,
         PsciFnId::PSCI_CPU_SUSPEND_32 | PsciFnId::PSCI_CPU_SUSPEND_64 => {
+            // The interrupt is taken once back in the guest, it may switch
+            // this cpu to another vCPU.
             wfi();
-            gicv3_handle_irq_el1();
             0
         },
         PsciFnId::PSCI_CPU_OFF_32 | PsciFnId::PSCI_CPU_OFF_64 => {
-            todo!();
+            this_cpu_data().arch_cpu.idle();
         }
         PsciFnId::PSCI_AFFINITY_INFO_32 | PsciFnId::PSCI_AFFINITY_INFO_64 => {
-            !get_cpu_data(arg0 as _).arch_cpu.psci_on as _
+            !this_zone().read().cpu_online(arg0 as _) as _
         }
         PsciFnId::PSCI_MIG_INFO_TYPE => PSCI_TOS_NOT_PRESENT_MP,
         PsciFnId::PSCI_FEATURES => psci_emulate_features_info(regs.usr[1]),
@@ -350,10 +369,9 @@ fn handle_psci_smc(
             let is_root = is_this_root_zone();
 
             for cpu_id in zone.read().cpu_set.iter_except(this_cpu_data().id) {
-                let target_cpu = get_cpu_data(cpu_id);
-                let _lock = target_cpu.ctrl_lock.lock();
-                target_cpu.zone = None;
-                send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+                if sched::remove_vcpu(cpu_id, zone_id) {
+                    send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+                }
             }
 
             this_cpu_data().zone = None;
@@ -366,6 +384,19 @@ fn handle_psci_smc(
 
             this_cpu_data().arch_cpu.idle();
         }
+        PsciFnId::PSCI_SYSTEM_RESET => {
+            if is_this_root_zone() {
+                // Resetting the board would take the other zones down too.
+                if num_zones() > 1 {
+                    warn!("root zone reset denied while other zones are running");
+                    return PSCI_DENIED;
+                }
+                psci::system_reset().unwrap();
+            }
+
+            this_zone().read().reboot();
+            this_cpu_data().run_vm();
+        }
 
         _ => {
             warn!("unsupported smc standard service {:#x?}", code);
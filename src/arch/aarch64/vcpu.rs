@@ -0,0 +1,283 @@
+//! Guest state of a vCPU, saved while another vCPU runs on its physical cpu.
+
+use core::arch::asm;
+
+use super::cpu::GeneralRegisters;
+use super::sysreg::{read_sysreg, write_sysreg};
+use crate::device::irqchip::gicv3::gicr::{restore_zone_ppis, save_zone_ppis};
+use crate::device::irqchip::gicv3::{read_lr, write_lr};
+use crate::error::HvResult;
+use aarch64_cpu::registers::{Readable, Writeable, ELR_EL2, SPSR_EL2};
+
+/// SPSR_EL2.M[4:0]: execution state, exception level and stack pointer.
+const SPSR_M: u64 = 0x1f;
+const SPSR_M_EL0T: u64 = 0b00000;
+const SPSR_M_EL1T: u64 = 0b00100;
+const SPSR_M_EL1H: u64 = 0b00101;
+/// SPSR_EL2.IL, illegal execution state.
+const SPSR_IL: u64 = 1 << 20;
+/// ICH_LR<n>_EL2.HW: deactivating the virtual irq deactivates the physical
+/// irq pINTID as well.
+const ICH_LR_HW: u64 = 1 << 61;
+const ICH_LR_VINTID: u64 = 0xffff_ffff;
+const ICH_LR_PINTID_SHIFT: u64 = 32;
+const ICH_LR_PINTID: u64 = 0x1fff << ICH_LR_PINTID_SHIFT;
+/// The defined fields of CPACR_EL1, TTA, SMEN, FPEN and ZEN. The rest is RES0.
+const CPACR_EL1_FIELDS: u64 = 1 << 28 | 0b11 << 24 | 0b11 << 20 | 0b11 << 16;
+
+macro_rules! sysreg_context {
+    ($(#[$doc:meta])* $name:ident { $($reg:ident),* $(,)? }) => {
+        $(#[$doc])*
+        #[allow(non_snake_case)]
+        #[repr(C)]
+        #[derive(Debug, Default, Clone, Copy)]
+        struct $name {
+            $($reg: u64,)*
+        }
+
+        impl $name {
+            fn save(&mut self) {
+                $(self.$reg = read_sysreg!($reg);)*
+            }
+
+            fn restore(&self) {
+                $(write_sysreg!($reg, self.$reg);)*
+            }
+        }
+    };
+}
+
+sysreg_context!(
+    /// EL1 and EL0 system registers.
+    El1Regs {
+        SCTLR_EL1,
+        CPACR_EL1,
+        TTBR0_EL1,
+        TTBR1_EL1,
+        TCR_EL1,
+        MAIR_EL1,
+        AMAIR_EL1,
+        VBAR_EL1,
+        CONTEXTIDR_EL1,
+        TPIDR_EL0,
+        TPIDRRO_EL0,
+        TPIDR_EL1,
+        SP_EL0,
+        SP_EL1,
+        ELR_EL1,
+        SPSR_EL1,
+        ESR_EL1,
+        FAR_EL1,
+        AFSR0_EL1,
+        AFSR1_EL1,
+        PAR_EL1,
+        CSSELR_EL1,
+        MDSCR_EL1,
+        CNTKCTL_EL1,
+    }
+);
+
+sysreg_context!(
+    /// Generic timer, the virtual counter offset included.
+    TimerRegs {
+        CNTV_CTL_EL0,
+        CNTV_CVAL_EL0,
+        CNTVOFF_EL2,
+        CNTP_CTL_EL0,
+        CNTP_CVAL_EL0,
+    }
+);
+
+/// FP/SIMD registers. The hypervisor itself is built without FP, so these
+/// stay untouched between guest exits and only move on vCPU switches.
+#[repr(C, align(16))]
+#[derive(Debug, Default, Clone, Copy)]
+struct FpRegs {
+    q: [u128; 32],
+    fpcr: u64,
+    fpsr: u64,
+}
+
+impl FpRegs {
+    fn save(&mut self) {
+        unsafe {
+            asm!(
+                ".arch_extension fp",
+                ".arch_extension simd",
+                "stp q0, q1, [{0}, #0x0]",
+                "stp q2, q3, [{0}, #0x20]",
+                "stp q4, q5, [{0}, #0x40]",
+                "stp q6, q7, [{0}, #0x60]",
+                "stp q8, q9, [{0}, #0x80]",
+                "stp q10, q11, [{0}, #0xa0]",
+                "stp q12, q13, [{0}, #0xc0]",
+                "stp q14, q15, [{0}, #0xe0]",
+                "stp q16, q17, [{0}, #0x100]",
+                "stp q18, q19, [{0}, #0x120]",
+                "stp q20, q21, [{0}, #0x140]",
+                "stp q22, q23, [{0}, #0x160]",
+                "stp q24, q25, [{0}, #0x180]",
+                "stp q26, q27, [{0}, #0x1a0]",
+                "stp q28, q29, [{0}, #0x1c0]",
+                "stp q30, q31, [{0}, #0x1e0]",
+                "mrs {1}, fpcr",
+                "mrs {2}, fpsr",
+                in(reg) self.q.as_mut_ptr(),
+                out(reg) self.fpcr,
+                out(reg) self.fpsr,
+                options(nostack),
+            );
+        }
+    }
+
+    fn restore(&self) {
+        unsafe {
+            asm!(
+                ".arch_extension fp",
+                ".arch_extension simd",
+                "ldp q0, q1, [{0}, #0x0]",
+                "ldp q2, q3, [{0}, #0x20]",
+                "ldp q4, q5, [{0}, #0x40]",
+                "ldp q6, q7, [{0}, #0x60]",
+                "ldp q8, q9, [{0}, #0x80]",
+                "ldp q10, q11, [{0}, #0xa0]",
+                "ldp q12, q13, [{0}, #0xc0]",
+                "ldp q14, q15, [{0}, #0xe0]",
+                "ldp q16, q17, [{0}, #0x100]",
+                "ldp q18, q19, [{0}, #0x120]",
+                "ldp q20, q21, [{0}, #0x140]",
+                "ldp q22, q23, [{0}, #0x160]",
+                "ldp q24, q25, [{0}, #0x180]",
+                "ldp q26, q27, [{0}, #0x1a0]",
+                "ldp q28, q29, [{0}, #0x1c0]",
+                "ldp q30, q31, [{0}, #0x1e0]",
+                "msr fpcr, {1}",
+                "msr fpsr, {2}",
+                in(reg) self.q.as_ptr(),
+                in(reg) self.fpcr,
+                in(reg) self.fpsr,
+                options(nostack),
+            );
+        }
+    }
+}
+
+/// Virtual cpu interface: list registers and active priorities, and the
+/// PPIs the guest set up in the redistributor.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct GicRegs {
+    lrs: [u64; 16],
+    vmcr: u64,
+    ap1r: [u64; 4],
+    ppi_enable: u32,
+    ppi_priority: [u32; 4],
+}
+
+impl GicRegs {
+    fn lr_num() -> usize {
+        (read_sysreg!(ich_vtr_el2) as usize & 0xf) + 1
+    }
+
+    fn ap1r_num() -> usize {
+        match (read_sysreg!(ich_vtr_el2) >> 29) + 1 {
+            7 => 4,
+            6 => 2,
+            _ => 1,
+        }
+    }
+
+    fn save(&mut self) {
+        for (i, lr) in self.lrs.iter_mut().enumerate().take(Self::lr_num()) {
+            *lr = read_lr(i);
+            write_lr(i, 0);
+        }
+        self.vmcr = read_sysreg!(ich_vmcr_el2);
+        let ap1r_num = Self::ap1r_num();
+        self.ap1r[0] = read_sysreg!(ICH_AP1R0_EL2);
+        if ap1r_num > 1 {
+            self.ap1r[1] = read_sysreg!(ICH_AP1R1_EL2);
+        }
+        if ap1r_num > 2 {
+            self.ap1r[2] = read_sysreg!(ICH_AP1R2_EL2);
+            self.ap1r[3] = read_sysreg!(ICH_AP1R3_EL2);
+        }
+        (self.ppi_enable, self.ppi_priority) = save_zone_ppis();
+    }
+
+    fn restore(&self) {
+        for (i, &lr) in self.lrs.iter().enumerate().take(Self::lr_num()) {
+            write_lr(i, lr);
+        }
+        write_sysreg!(ich_vmcr_el2, self.vmcr);
+        let ap1r_num = Self::ap1r_num();
+        write_sysreg!(ICH_AP1R0_EL2, self.ap1r[0]);
+        if ap1r_num > 1 {
+            write_sysreg!(ICH_AP1R1_EL2, self.ap1r[1]);
+        }
+        if ap1r_num > 2 {
+            write_sysreg!(ICH_AP1R2_EL2, self.ap1r[2]);
+            write_sysreg!(ICH_AP1R3_EL2, self.ap1r[3]);
+        }
+        restore_zone_ppis(self.ppi_enable, &self.ppi_priority);
+    }
+}
+
+/// Everything a guest cpu keeps in the physical cpu. The layout is part of
+/// the checkpoint ABI, see `HvVcpuSnapshot`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VcpuContext {
+    usr: [u64; 31],
+    elr_el2: u64,
+    spsr_el2: u64,
+    el1: El1Regs,
+    timer: TimerRegs,
+    fp: FpRegs,
+    gic: GicRegs,
+}
+
+impl VcpuContext {
+    /// Save the state of the guest that just exited with `regs`.
+    pub fn save(&mut self, regs: &GeneralRegisters) {
+        self.usr = regs.usr;
+        self.elr_el2 = ELR_EL2.get();
+        self.spsr_el2 = SPSR_EL2.get();
+        self.el1.save();
+        self.timer.save();
+        self.fp.save();
+        self.gic.save();
+    }
+
+    /// Check a context that comes from outside the hypervisor, e.g. a
+    /// checkpoint, before it is restored: the guest must continue in AArch64
+    /// at EL1 or EL0, and its list registers may only be tied to the physical
+    /// irqs for which `owns_irq` holds. RES0 bits of CPACR_EL1 are cleared,
+    /// CNTVOFF_EL2 only offsets the virtual counter of this guest.
+    pub fn check(&mut self, owns_irq: impl Fn(u32) -> bool) -> HvResult {
+        match self.spsr_el2 & SPSR_M {
+            SPSR_M_EL0T | SPSR_M_EL1T | SPSR_M_EL1H if self.spsr_el2 & SPSR_IL == 0 => {}
+            _ => return hv_result_err!(EINVAL, format!("Bad SPSR_EL2 {:#x}", self.spsr_el2)),
+        }
+        for &lr in self.gic.lrs.iter().filter(|&&lr| lr & ICH_LR_HW != 0) {
+            // As injected by `inject_irq`: an SPI mapped to itself.
+            let pintid = (lr & ICH_LR_PINTID) >> ICH_LR_PINTID_SHIFT;
+            if pintid != lr & ICH_LR_VINTID || pintid < 32 || !owns_irq(pintid as _) {
+                return hv_result_err!(EINVAL, format!("Bad list register {:#x}", lr));
+            }
+        }
+        self.el1.CPACR_EL1 &= CPACR_EL1_FIELDS;
+        Ok(())
+    }
+
+    /// Load the saved state, the guest continues with `regs` on return.
+    pub fn restore(&self, regs: &mut GeneralRegisters) {
+        regs.usr = self.usr;
+        ELR_EL2.set(self.elr_el2);
+        SPSR_EL2.set(self.spsr_el2);
+        self.el1.restore();
+        self.timer.restore();
+        self.fp.restore();
+        self.gic.restore();
+    }
+}
@@ -6,7 +6,7 @@ use crate::{
     config::*,
     device::virtio_trampoline::{mmio_virtio_handler, VIRTIO_BRIDGE},
     error::HvResult,
-    memory::{GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion},
+    memory::{GuestPhysAddr, HostPhysAddr, MemoryRegion},
     zone::Zone,
 };
 
@@ -15,10 +15,7 @@ impl Zone {
         // The first memory region is used to map the guest physical memory.
 
         for mem_region in mem_regions.iter() {
-            let mut flags = MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE;
-            if mem_region.mem_type == MEM_TYPE_IO {
-                flags |= MemFlags::IO;
-            }
+            let flags = mem_region.mem_flags();
             match mem_region.mem_type {
                 MEM_TYPE_RAM | MEM_TYPE_IO => {
                     self.gpm.insert(MemoryRegion::new_with_offset_mapper(
@@ -28,6 +25,8 @@ impl Zone {
                         flags,
                     ))?
                 }
+                // Mapped by `ivshmem_init` with the permissions of the zone.
+                MEM_TYPE_IVSHMEM => {}
                 MEM_TYPE_VIRTIO => {
                     self.mmio_region_register(
                         mem_region.physical_start as _,
@@ -37,7 +36,10 @@ impl Zone {
                     );
                 }
                 _ => {
-                    panic!("Unsupported memory type: {}", mem_region.mem_type)
+                    return hv_result_err!(
+                        EINVAL,
+                        format!("Unsupported memory type: {}", mem_region.mem_type)
+                    );
                 }
             }
         }
@@ -52,7 +54,7 @@ impl Zone {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub struct HvArchZoneConfig {
     pub gicd_base: usize,
     pub gicr_base: usize,
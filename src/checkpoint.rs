@@ -0,0 +1,281 @@
+//! Zone checkpoints.
+//!
+//! `HyperCallCode::HvZoneCheckpoint` pauses a zone and writes it to a buffer
+//! of the root zone: an `HvCheckpointHeader`, the config of the zone in the
+//! format of `HvZoneConfig::parse` padded to 8 bytes, the `HvIrqState`s of
+//! its interrupts, an `HvVcpuSnapshot` for each cpu of the zone in ascending
+//! order, then the contents of its RAM regions in config order.
+//! `HyperCallCode::HvZoneRestore` creates the zone again from such a buffer,
+//! it continues where it was paused instead of booting.
+//!
+//! Devices outside the zone's memory and interrupts, e.g. virtio backends or
+//! mailbox messages in flight, are not part of a checkpoint.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::slice;
+use spin::RwLock;
+
+use crate::arch::cache::{dcache_clean_invalidate_range, icache_invalidate_all};
+use crate::arch::vcpu::VcpuContext;
+use crate::config::{
+    HvConfigMemoryRegion, HvZoneConfig, CONFIG_MAX_INTERRUPTS, CONFIG_MAX_SIZE, MEM_TYPE_RAM,
+};
+use crate::device::irqchip::gicv3::HvIrqState;
+use crate::error::HvResult;
+use crate::hypercall::HV_ABI_VERSION;
+use crate::memory::addr::{phys_to_virt, GuestPhysAddr};
+use crate::percpu::this_zone;
+use crate::sched::{self, VcpuState};
+use crate::zone::{zone_create, zone_shutdown, Zone};
+
+pub const CHECKPOINT_MAGIC: u64 = u64::from_le_bytes(*b"HVCKPT\0\0");
+
+/// The vCPU is off.
+pub const VCPU_STATE_OFF: u32 = 0;
+/// The vCPU is on and starts from `HvVcpuSnapshot::entry`.
+pub const VCPU_STATE_RESET: u32 = 1;
+/// The vCPU continues from `HvVcpuSnapshot::ctx`.
+pub const VCPU_STATE_SAVED: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvCheckpointHeader {
+    /// Size of the buffer, set by the root zone. Replaced with the size of
+    /// the checkpoint, also when the buffer is too small for it.
+    pub size: u64,
+    pub magic: u64,
+    /// `HV_ABI_VERSION` of the hypervisor that wrote the checkpoint.
+    pub abi_version: u32,
+    pub num_vcpus: u32,
+    /// Size of the config of the zone, with its current cpus and without
+    /// images.
+    pub config_size: u32,
+    pub num_irqs: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvVcpuSnapshot {
+    pub cpu_id: u32,
+    /// One of `VCPU_STATE_*`.
+    pub state: u32,
+    pub entry: u64,
+    pub ctx: VcpuContext,
+}
+
+impl HvVcpuSnapshot {
+    fn new(cpu_id: usize, state: VcpuState) -> Self {
+        let (state, entry, ctx) = match state {
+            VcpuState::Off => (VCPU_STATE_OFF, 0, VcpuContext::default()),
+            VcpuState::Reset(entry) => (VCPU_STATE_RESET, entry, VcpuContext::default()),
+            VcpuState::Context(ctx) => (VCPU_STATE_SAVED, 0, ctx),
+        };
+        Self {
+            cpu_id: cpu_id as _,
+            state,
+            entry: entry as _,
+            ctx,
+        }
+    }
+
+    /// The state to restore in a zone created from `config`. Saved contexts
+    /// come from the root zone and are checked before they reach the cpu.
+    fn state(&self, config: &HvZoneConfig) -> HvResult<VcpuState> {
+        match self.state {
+            VCPU_STATE_OFF => Ok(VcpuState::Off),
+            VCPU_STATE_RESET => Ok(VcpuState::Reset(self.entry as _)),
+            VCPU_STATE_SAVED => {
+                let mut ctx = self.ctx;
+                ctx.check(|irq| config.interrupts().contains(&irq))?;
+                Ok(VcpuState::Context(ctx))
+            }
+            _ => hv_result_err!(EINVAL, format!("Bad state of vcpu {}", self.cpu_id)),
+        }
+    }
+}
+
+fn ram_regions(config: &HvZoneConfig) -> impl Iterator<Item = &HvConfigMemoryRegion> {
+    config
+        .memory_regions()
+        .iter()
+        .filter(|region| region.mem_type == MEM_TYPE_RAM)
+}
+
+/// Offset of the interrupt states in a checkpoint.
+fn irqs_offset(config_size: usize) -> usize {
+    size_of::<HvCheckpointHeader>() + ((config_size + 7) & !7)
+}
+
+/// Size of a checkpoint, EINVAL if it overflows with the sizes found in a
+/// corrupted one.
+fn checkpoint_size(config: &HvZoneConfig, config_size: usize, num_irqs: usize) -> HvResult<usize> {
+    let size = || {
+        let irqs_size = num_irqs.checked_mul(size_of::<HvIrqState>())?;
+        let vcpus_size = config
+            .cpus()
+            .len()
+            .checked_mul(size_of::<HvVcpuSnapshot>())?;
+        let mut size = irqs_offset(config_size)
+            .checked_add(irqs_size)?
+            .checked_add(vcpus_size)?;
+        for region in ram_regions(config) {
+            size = size.checked_add(region.size as usize)?;
+        }
+        Some(size)
+    };
+    size().ok_or(hv_err!(EINVAL, "Checkpoint size overflows"))
+}
+
+/// Pause `zone` and write its checkpoint to `buf` in the root zone. The zone
+/// continues afterwards, unless it was paused already. Returns the size of
+/// the checkpoint.
+pub fn checkpoint(zone: &Arc<RwLock<Zone>>, buf: GuestPhysAddr) -> HvResult<usize> {
+    let zone = zone.read();
+    let was_paused = zone.is_suspended();
+    zone.suspend();
+    let ret = write_checkpoint(&zone, buf);
+    if !was_paused {
+        zone.resume();
+    }
+    ret
+}
+
+fn write_checkpoint(zone: &Zone, buf: GuestPhysAddr) -> HvResult<usize> {
+    let mut config = match &zone.config {
+        Some(config) => config.clone(),
+        None => return hv_result_err!(EINVAL, "Zone has no config"),
+    };
+    config.set_cpus(zone.cpu_set.bitmap);
+    config.kernel_image = 0;
+    config.dtb_image = 0;
+    config.initrd_image = 0;
+    let config_bytes = config.to_bytes();
+    let irqs: Vec<HvIrqState> = zone.arch_irqchip_save();
+
+    let root = this_zone();
+    let root = root.read();
+    let capacity: u64 = unsafe { root.gpm.read_guest(buf)? };
+    let size = checkpoint_size(&config, config_bytes.len(), irqs.len())?;
+    if capacity < size as u64 {
+        root.gpm.write_guest(buf, &(size as u64))?;
+        return hv_result_err!(
+            E2BIG,
+            format!("Checkpoint of zone {} needs {:#x} bytes", zone.id, size)
+        );
+    }
+
+    root.gpm
+        .copy_to_guest(buf + size_of::<HvCheckpointHeader>(), &config_bytes)?;
+    let mut offset = irqs_offset(config_bytes.len());
+    for irq in irqs.iter() {
+        root.gpm.write_guest(buf + offset, irq)?;
+        offset += size_of::<HvIrqState>();
+    }
+    for cpu_id in zone.cpu_set.iter() {
+        let state = match sched::vcpu_state(cpu_id, zone.id) {
+            Some(state) => state,
+            None => return hv_result_err!(EBUSY, format!("Cpu {} still running", cpu_id)),
+        };
+        root.gpm
+            .write_guest(buf + offset, &HvVcpuSnapshot::new(cpu_id, state))?;
+        offset += size_of::<HvVcpuSnapshot>();
+    }
+    for region in ram_regions(&config) {
+        let vaddr = phys_to_virt(region.physical_start as _);
+        let src = unsafe { slice::from_raw_parts(vaddr as *const u8, region.size as _) };
+        root.gpm.copy_to_guest(buf + offset, src)?;
+        offset += region.size as usize;
+    }
+
+    let header = HvCheckpointHeader {
+        size: size as _,
+        magic: CHECKPOINT_MAGIC,
+        abi_version: HV_ABI_VERSION,
+        num_vcpus: zone.cpu_set.iter().count() as _,
+        config_size: config_bytes.len() as _,
+        num_irqs: irqs.len() as _,
+    };
+    root.gpm.write_guest(buf, &header)?;
+    info!("zone {} checkpointed, {:#x} bytes", zone.id, size);
+    Ok(size)
+}
+
+/// Create the zone saved at `buf` in the root zone and let it continue.
+pub fn restore(buf: GuestPhysAddr) -> HvResult<Arc<RwLock<Zone>>> {
+    let header: HvCheckpointHeader = unsafe { this_zone().read().gpm.read_guest(buf)? };
+    if header.magic != CHECKPOINT_MAGIC {
+        return hv_result_err!(EINVAL, "Not a checkpoint");
+    }
+    if header.abi_version != HV_ABI_VERSION {
+        return hv_result_err!(
+            EINVAL,
+            format!("Checkpoint from ABI version {}", header.abi_version)
+        );
+    }
+    let (config_size, num_irqs) = (header.config_size as usize, header.num_irqs as usize);
+    if config_size > CONFIG_MAX_SIZE || num_irqs > CONFIG_MAX_INTERRUPTS {
+        return hv_result_err!(EINVAL, "Corrupted checkpoint");
+    }
+    let mut config_bytes = vec![0; config_size];
+    this_zone()
+        .read()
+        .gpm
+        .copy_from_guest(buf + size_of::<HvCheckpointHeader>(), &mut config_bytes)?;
+    let config = HvZoneConfig::parse(&config_bytes)?;
+    if header.num_vcpus as usize != config.cpus().len()
+        || header.size != checkpoint_size(&config, config_size, num_irqs)? as u64
+    {
+        return hv_result_err!(EINVAL, "Corrupted checkpoint");
+    }
+
+    let mut offset = irqs_offset(config_size);
+    let mut irqs = Vec::new();
+    for _ in 0..num_irqs {
+        let irq: HvIrqState = unsafe { this_zone().read().gpm.read_guest(buf + offset)? };
+        irqs.push(irq);
+        offset += size_of::<HvIrqState>();
+    }
+    let mut vcpus = Vec::new();
+    for cpu_id in config.cpus() {
+        let snapshot: HvVcpuSnapshot = unsafe { this_zone().read().gpm.read_guest(buf + offset)? };
+        if snapshot.cpu_id as u64 != cpu_id {
+            return hv_result_err!(EINVAL, "Corrupted checkpoint");
+        }
+        vcpus.push((cpu_id as usize, snapshot.state(&config)?));
+        offset += size_of::<HvVcpuSnapshot>();
+    }
+
+    let zone = zone_create(&config)?;
+    if let Err(e) = load_ram(&config, buf + offset) {
+        zone_shutdown(zone);
+        return Err(e);
+    }
+    let zone_r = zone.read();
+    zone_r.arch_irqchip_restore(&irqs);
+    for (cpu_id, state) in vcpus {
+        if !sched::restore_vcpu(cpu_id, zone_r.id, state) {
+            warn!("restore: cpu {} already on", cpu_id);
+        }
+    }
+    info!("zone {} restored", zone_r.id);
+    drop(zone_r);
+    Ok(zone)
+}
+
+/// Copy the RAM regions of `config` from `buf` in the root zone.
+fn load_ram(config: &HvZoneConfig, buf: GuestPhysAddr) -> HvResult {
+    let root = this_zone();
+    let root = root.read();
+    let mut offset = 0;
+    for region in ram_regions(config) {
+        let vaddr = phys_to_virt(region.physical_start as _);
+        let dst = unsafe { slice::from_raw_parts_mut(vaddr as *mut u8, region.size as _) };
+        root.gpm.copy_from_guest(buf + offset, dst)?;
+        dcache_clean_invalidate_range(vaddr, region.size as _);
+        offset += region.size as usize;
+    }
+    icache_invalidate_all();
+    Ok(())
+}
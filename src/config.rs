@@ -1,14 +1,83 @@
+use alloc::string::String;
 use alloc::vec::Vec;
+use core::mem::{size_of, size_of_val};
+use core::ptr::read_unaligned;
+use core::slice;
 use spin::Once;
 
-use crate::{arch::zone::HvArchZoneConfig, platform};
+use crate::{
+    arch::zone::HvArchZoneConfig,
+    consts::INVALID_ADDRESS,
+    error::HvResult,
+    memory::{GuestPhysAddr, MemFlags},
+    platform,
+    zone::Zone,
+};
 
 pub const MEM_TYPE_RAM: u32 = 0;
 pub const MEM_TYPE_IO: u32 = 1;
 pub const MEM_TYPE_VIRTIO: u32 = 2;
+/// Memory shared between zones, described further by a `HvIvshmemConfig`.
+pub const MEM_TYPE_IVSHMEM: u32 = 3;
 
-pub const CONFIG_MAX_MEMORY_REGIONS: usize = 16;
-pub const CONFIG_MAX_INTERRUPTS: usize = 32;
+/// The zone may not write to the region.
+pub const MEM_FLAG_READONLY: u32 = 1 << 0;
+/// The zone may not execute from the region. IO regions never are executable.
+pub const MEM_FLAG_NOEXEC: u32 = 1 << 1;
+/// Map the region as device memory, which IO regions are by default.
+pub const MEM_FLAG_DEVICE: u32 = 1 << 2;
+/// Map the region as normal non-cacheable memory, RAM is cacheable by default.
+/// Only valid on RAM and shared memory: IO and virtio regions stay device
+/// memory, so zones with this flag on them are rejected.
+pub const MEM_FLAG_UNCACHED: u32 = 1 << 3;
+pub const MEM_FLAGS_ALL: u32 =
+    MEM_FLAG_READONLY | MEM_FLAG_NOEXEC | MEM_FLAG_DEVICE | MEM_FLAG_UNCACHED;
+
+pub const ZONE_CONFIG_MAGIC: u32 = u32::from_le_bytes(*b"HVZC");
+pub const ZONE_CONFIG_VERSION: u32 = 1;
+/// Largest zone config or device tree the hypervisor reads.
+pub const CONFIG_MAX_SIZE: usize = 64 * 1024;
+/// Magic of a device tree blob, read as big endian.
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// Mask of the cpus of the zone, a `u64`. Required.
+pub const CONFIG_SECTION_CPUS: u32 = 1;
+/// `HvConfigMemoryRegion`s.
+pub const CONFIG_SECTION_MEMORY: u32 = 2;
+/// `u32` irq numbers.
+pub const CONFIG_SECTION_IRQS: u32 = 3;
+/// A `HvBootConfig`. Required.
+pub const CONFIG_SECTION_BOOT: u32 = 4;
+/// A `HvArchZoneConfig`. Required.
+pub const CONFIG_SECTION_ARCH: u32 = 5;
+/// `HvIvshmemConfig`s.
+pub const CONFIG_SECTION_IVSHMEM: u32 = 6;
+/// `HvChannelConfig`s.
+pub const CONFIG_SECTION_CHANNELS: u32 = 7;
+/// A `HvWatchdogConfig`.
+pub const CONFIG_SECTION_WATCHDOG: u32 = 8;
+/// A `HvSchedConfig` and `HvSchedWindow`s.
+pub const CONFIG_SECTION_SCHED: u32 = 9;
+/// A `HvGuestDtbHeader`, the virtio interrupts and the bootargs. The
+/// hypervisor generates the device tree of the zone at `dtb_load_paddr`.
+pub const CONFIG_SECTION_GUEST_DTB: u32 = 10;
+/// Flag of sections the hypervisor skips if it doesn't know them.
+pub const CONFIG_SECTION_OPTIONAL: u32 = 1 << 31;
+
+pub const CONFIG_MAX_MEMORY_REGIONS: usize = 64;
+pub const CONFIG_MAX_INTERRUPTS: usize = 988;
+pub const CONFIG_MAX_IVSHMEM: usize = 4;
+pub const CONFIG_MAX_CHANNELS: usize = 4;
+pub const CONFIG_CHANNEL_NAME_LEN: usize = 16;
+pub const CONFIG_MAX_SCHED_WINDOWS: usize = 8;
+
+/// The zone may write to the shared memory.
+pub const IVSHMEM_FLAG_WRITE: u32 = 1 << 0;
+
+pub const WATCHDOG_ACTION_REBOOT: u32 = 0;
+pub const WATCHDOG_ACTION_SHUTDOWN: u32 = 1;
+/// Inject `notify_irq` into the root zone.
+pub const WATCHDOG_ACTION_NOTIFY_ROOT: u32 = 2;
 
 // pub const CONFIG_KERNEL_ARGS_MAXLEN: usize = 256;
 
@@ -17,83 +86,564 @@ pub const CONFIG_MAX_INTERRUPTS: usize = 32;
 
 pub struct HvConfigMemoryRegion {
     pub mem_type: u32,
+    /// `MEM_FLAG_*` of RAM and IO regions.
+    pub flags: u32,
     pub physical_start: u64,
     pub virtual_start: u64,
     pub size: u64,
 }
 
+/// A zone's end of a shared memory region, matched with the `MEM_TYPE_IVSHMEM`
+/// memory region starting at `physical_start`. Peers of the same region are
+/// told apart by `peer_id`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvIvshmemConfig {
+    pub physical_start: u64,
+    /// Guest physical address of the doorbell page.
+    pub doorbell_base: u64,
+    pub peer_id: u32,
+    /// Virtual irq injected into this zone when a peer rings it.
+    pub irq: u32,
+    pub flags: u32,
+}
+
+/// A mailbox channel, connecting the two zones that declare the same `name`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvChannelConfig {
+    /// NUL padded.
+    pub name: [u8; CONFIG_CHANNEL_NAME_LEN],
+    /// Virtual irq injected into this zone when a message arrives.
+    pub irq: u32,
+}
+
+/// Watchdog of a zone, fed with heartbeat hypercalls.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvWatchdogConfig {
+    /// 0 if the zone has no watchdog.
+    pub timeout_ms: u32,
+    /// What to do when the timeout expires, a `WATCHDOG_ACTION_*`.
+    pub action: u32,
+    pub notify_irq: u32,
+}
+
+/// A window of the cyclic schedule, in which the zone runs on its cpus. The
+/// schedule repeats every `HvZoneConfig::major_frame_ms`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvSchedWindow {
+    /// Start of the window in the major frame.
+    pub offset_ms: u32,
+    pub duration_ms: u32,
+}
+
 impl HvConfigMemoryRegion {
     pub fn new_empty() -> Self {
         Self {
             mem_type: 0,
+            flags: 0,
             physical_start: 0,
             virtual_start: 0,
             size: 0,
         }
     }
+
+    /// Stage 2 permissions and memory type of a RAM or IO region.
+    pub fn mem_flags(&self) -> MemFlags {
+        let mut flags = MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE;
+        if self.mem_type == MEM_TYPE_IO {
+            flags = MemFlags::READ | MemFlags::WRITE | MemFlags::IO;
+        }
+        if self.flags & MEM_FLAG_READONLY != 0 {
+            flags.remove(MemFlags::WRITE);
+        }
+        if self.flags & MEM_FLAG_NOEXEC != 0 {
+            flags.remove(MemFlags::EXECUTE);
+        }
+        if self.flags & MEM_FLAG_DEVICE != 0 {
+            flags |= MemFlags::DEVICE;
+        }
+        if self.flags & MEM_FLAG_UNCACHED != 0 {
+            flags |= MemFlags::NO_CACHE;
+        }
+        flags
+    }
+
+    /// The `MEM_FLAG_*` of a region mapped with `flags`.
+    pub fn flags_of(flags: MemFlags) -> u32 {
+        let mut ret = 0;
+        if !flags.contains(MemFlags::WRITE) {
+            ret |= MEM_FLAG_READONLY;
+        }
+        if !flags.contains(MemFlags::EXECUTE) && !flags.contains(MemFlags::IO) {
+            ret |= MEM_FLAG_NOEXEC;
+        }
+        if flags.contains(MemFlags::DEVICE) {
+            ret |= MEM_FLAG_DEVICE;
+        }
+        if flags.contains(MemFlags::NO_CACHE) {
+            ret |= MEM_FLAG_UNCACHED;
+        }
+        ret
+    }
+}
+
+/// Header of a zone config passed to the hypervisor, followed by sections
+/// up to `size`. Each section is a `HvConfigSectionHeader` and its payload,
+/// padded to 8 bytes.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvZoneConfigHeader {
+    /// `ZONE_CONFIG_MAGIC`.
+    pub magic: u32,
+    /// `ZONE_CONFIG_VERSION` of the format.
+    pub version: u32,
+    /// Size of the config, this header included.
+    pub size: u32,
+    pub zone_id: u32,
 }
 
 #[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvConfigSectionHeader {
+    /// One of `CONFIG_SECTION_*`, with `CONFIG_SECTION_OPTIONAL` if an older
+    /// hypervisor may ignore it.
+    pub kind: u32,
+    /// Size of the payload following this header.
+    pub size: u32,
+}
+
+/// Payload of `CONFIG_SECTION_BOOT`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvBootConfig {
+    pub entry_point: u64,
+    pub kernel_load_paddr: u64,
+    pub kernel_size: u64,
+    pub dtb_load_paddr: u64,
+    pub dtb_size: u64,
+    pub initrd_load_paddr: u64,
+    pub initrd_size: u64,
+    pub kernel_image: u64,
+    pub dtb_image: u64,
+    pub initrd_image: u64,
+}
+
+/// Payload of `CONFIG_SECTION_SCHED`, followed by its `HvSchedWindow`s.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvSchedConfig {
+    pub time_slice_ms: u32,
+    pub major_frame_ms: u32,
+}
+
+/// Payload of `CONFIG_SECTION_GUEST_DTB`, followed by `num_virtio_irqs`
+/// `u32` interrupts, one for each virtio region in config order, then
+/// `bootargs_size` bytes of the `/chosen` bootargs.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvGuestDtbHeader {
+    pub num_virtio_irqs: u32,
+    pub bootargs_size: u32,
+}
+
+/// Device tree the hypervisor generates for a zone.
+#[derive(Debug, Clone)]
+pub struct GuestDtbConfig {
+    /// Interrupt of each virtio region of the zone, in config order.
+    pub virtio_irqs: Vec<u32>,
+    pub bootargs: String,
+}
+
+/// Config of a zone, parsed from the sections of a `HvZoneConfigHeader`.
 #[derive(Debug, Clone)]
 pub struct HvZoneConfig {
     pub zone_id: u32,
     cpus: u64,
-    num_memory_regions: u32,
-    memory_regions: [HvConfigMemoryRegion; CONFIG_MAX_MEMORY_REGIONS],
-    num_interrupts: u32,
-    interrupts: [u32; CONFIG_MAX_INTERRUPTS],
+    memory_regions: Vec<HvConfigMemoryRegion>,
+    interrupts: Vec<u32>,
     pub entry_point: u64,
     pub kernel_load_paddr: u64,
     pub kernel_size: u64,
     pub dtb_load_paddr: u64,
     pub dtb_size: u64,
+    pub initrd_load_paddr: u64,
+    pub initrd_size: u64,
+    /// Buffers in the caller's memory holding the images, which the
+    /// hypervisor copies to the load addresses above. 0 if already loaded.
+    pub kernel_image: u64,
+    pub dtb_image: u64,
+    pub initrd_image: u64,
+    ivshmem: Vec<HvIvshmemConfig>,
+    channels: Vec<HvChannelConfig>,
+    pub watchdog: HvWatchdogConfig,
+    /// Time slice of the zone's vCPUs in milliseconds. Zones with a time
+    /// slice may share cpus with each other, 0 keeps the cpus exclusive.
+    pub time_slice_ms: u32,
+    /// Period of the cyclic schedule, 0 if the zone has no windows. Zones
+    /// with windows may share cpus with zones that have windows or a time
+    /// slice; the latter run in the time no window takes.
+    pub major_frame_ms: u32,
+    sched_windows: Vec<HvSchedWindow>,
+    /// Generate the device tree of the zone instead of loading one.
+    pub guest_dtb: Option<GuestDtbConfig>,
 
     pub arch: HvArchZoneConfig,
 }
 
+/// Size of `T` as a section payload or the header of one.
+const fn padded_size<T>() -> usize {
+    (size_of::<T>() + 7) & !7
+}
+
+/// Read the items of an array section.
+fn read_items<T: Copy>(payload: &[u8]) -> HvResult<Vec<T>> {
+    if payload.len() % size_of::<T>() != 0 {
+        return hv_result_err!(EINVAL, "Truncated config section");
+    }
+    Ok(payload
+        .chunks_exact(size_of::<T>())
+        .map(|item| unsafe { read_unaligned(item.as_ptr() as *const T) })
+        .collect())
+}
+
+fn read_item<T: Copy>(payload: &[u8]) -> HvResult<T> {
+    match read_items(payload)?.as_slice() {
+        [item] => Ok(*item),
+        _ => hv_result_err!(EINVAL, "Bad size of config section"),
+    }
+}
+
+fn push_bytes<T: Copy>(buf: &mut Vec<u8>, items: &[T]) {
+    let bytes = unsafe { slice::from_raw_parts(items.as_ptr() as *const u8, size_of_val(items)) };
+    buf.extend_from_slice(bytes);
+}
+
+/// Append a section of `kind` holding `head` and `items` to `buf`.
+fn push_section<H: Copy, T: Copy>(buf: &mut Vec<u8>, kind: u32, head: &[H], items: &[T]) {
+    let header = HvConfigSectionHeader {
+        kind,
+        size: (size_of_val(head) + size_of_val(items)) as _,
+    };
+    push_bytes(buf, &[header]);
+    push_bytes(buf, head);
+    push_bytes(buf, items);
+    buf.resize((buf.len() + 7) & !7, 0);
+}
+
+fn read_guest_dtb(payload: &[u8]) -> HvResult<GuestDtbConfig> {
+    let head_size = size_of::<HvGuestDtbHeader>();
+    if payload.len() < head_size {
+        return hv_result_err!(EINVAL, "Truncated config section");
+    }
+    let head: HvGuestDtbHeader = read_item(&payload[..head_size])?;
+    let irqs_end = head_size + head.num_virtio_irqs as usize * size_of::<u32>();
+    if payload.len() != irqs_end + head.bootargs_size as usize {
+        return hv_result_err!(EINVAL, "Bad size of config section");
+    }
+    let bootargs = core::str::from_utf8(&payload[irqs_end..])
+        .map_err(|_| hv_err!(EINVAL, "Bootargs not UTF-8"))?;
+    Ok(GuestDtbConfig {
+        virtio_irqs: read_items(&payload[head_size..irqs_end])?,
+        bootargs: bootargs.trim_end_matches('\0').into(),
+    })
+}
+
 impl HvZoneConfig {
+    /// Config of a zone whose images are in place already.
     pub fn new(
         zone_id: u32,
         cpus: u64,
-        num_memory_regions: u32,
-        memory_regions: [HvConfigMemoryRegion; CONFIG_MAX_MEMORY_REGIONS],
-        num_interrupts: u32,
-        interrupts: [u32; CONFIG_MAX_INTERRUPTS],
+        memory_regions: Vec<HvConfigMemoryRegion>,
+        interrupts: Vec<u32>,
         entry_point: u64,
         kernel_load_paddr: u64,
-        kernel_size:u64,
-        dtb_load_paddr:u64,
-        dtb_size: u64,
+        dtb_load_paddr: u64,
         arch: HvArchZoneConfig,
     ) -> Self {
         Self {
             zone_id,
             cpus,
-            num_memory_regions,
             memory_regions,
-            num_interrupts,
             interrupts,
             entry_point,
             kernel_load_paddr,
-            kernel_size,
+            kernel_size: INVALID_ADDRESS as _,
             dtb_load_paddr,
-            dtb_size,
+            dtb_size: INVALID_ADDRESS as _,
+            initrd_load_paddr: INVALID_ADDRESS as _,
+            initrd_size: 0,
+            kernel_image: 0,
+            dtb_image: 0,
+            initrd_image: 0,
+            ivshmem: Vec::new(),
+            channels: Vec::new(),
+            watchdog: HvWatchdogConfig {
+                timeout_ms: 0,
+                action: 0,
+                notify_irq: 0,
+            },
+            time_slice_ms: 0,
+            major_frame_ms: 0,
+            sched_windows: Vec::new(),
+            guest_dtb: None,
             arch,
         }
     }
 
-    pub fn memory_regions(&self) -> &[HvConfigMemoryRegion] {
-        if self.num_memory_regions > CONFIG_MAX_MEMORY_REGIONS as u32 {
-            panic!("Too many memory regions");
+    /// Parse and check the zone config in `bytes`. The cpus, boot and arch
+    /// sections are required, the others are empty if left out.
+    pub fn parse(bytes: &[u8]) -> HvResult<Self> {
+        if bytes.len() < size_of::<HvZoneConfigHeader>() {
+            return hv_result_err!(EINVAL, "Truncated zone config");
+        }
+        let header: HvZoneConfigHeader =
+            unsafe { read_unaligned(bytes.as_ptr() as *const HvZoneConfigHeader) };
+        if header.magic != ZONE_CONFIG_MAGIC {
+            return hv_result_err!(EINVAL, "Not a zone config");
+        }
+        if header.version != ZONE_CONFIG_VERSION {
+            return hv_result_err!(
+                EINVAL,
+                format!("Unsupported zone config version {}", header.version)
+            );
+        }
+        if header.size as usize > bytes.len() {
+            return hv_result_err!(EINVAL, "Truncated zone config");
+        }
+
+        let (mut cpus, mut boot, mut arch) = (None, None, None);
+        let (mut memory_regions, mut interrupts) = (Vec::new(), Vec::new());
+        let (mut ivshmem, mut channels, mut watchdog) = (Vec::new(), Vec::new(), None);
+        let (mut sched, mut sched_windows) = (None, Vec::new());
+        let mut guest_dtb = None;
+        let mut seen = 0u64;
+        let mut offset = padded_size::<HvZoneConfigHeader>();
+        while offset < header.size as usize {
+            let body = offset + size_of::<HvConfigSectionHeader>();
+            if body > header.size as usize {
+                return hv_result_err!(EINVAL, "Truncated zone config");
+            }
+            let section: HvConfigSectionHeader =
+                unsafe { read_unaligned(bytes[offset..].as_ptr() as *const _) };
+            let end = body
+                .checked_add(section.size as usize)
+                .filter(|&end| end <= header.size as usize)
+                .ok_or(hv_err!(EINVAL, "Truncated config section"))?;
+            let payload = &bytes[body..end];
+            offset = (end + 7) & !7;
+
+            let kind = section.kind & !CONFIG_SECTION_OPTIONAL;
+            if kind < 64 {
+                if seen & 1 << kind != 0 {
+                    return hv_result_err!(EINVAL, format!("Config section {} repeated", kind));
+                }
+                seen |= 1 << kind;
+            }
+            match kind {
+                CONFIG_SECTION_CPUS => cpus = Some(read_item(payload)?),
+                CONFIG_SECTION_MEMORY => memory_regions = read_items(payload)?,
+                CONFIG_SECTION_IRQS => interrupts = read_items(payload)?,
+                CONFIG_SECTION_BOOT => boot = Some(read_item::<HvBootConfig>(payload)?),
+                CONFIG_SECTION_ARCH => arch = Some(read_item(payload)?),
+                CONFIG_SECTION_IVSHMEM => ivshmem = read_items(payload)?,
+                CONFIG_SECTION_CHANNELS => channels = read_items(payload)?,
+                CONFIG_SECTION_WATCHDOG => watchdog = Some(read_item(payload)?),
+                CONFIG_SECTION_SCHED => {
+                    let head_size = size_of::<HvSchedConfig>();
+                    if payload.len() < head_size {
+                        return hv_result_err!(EINVAL, "Truncated config section");
+                    }
+                    sched = Some(read_item::<HvSchedConfig>(&payload[..head_size])?);
+                    sched_windows = read_items(&payload[head_size..])?;
+                }
+                CONFIG_SECTION_GUEST_DTB => guest_dtb = Some(read_guest_dtb(payload)?),
+                _ if section.kind & CONFIG_SECTION_OPTIONAL != 0 => {
+                    warn!("zone config: optional section {} ignored", kind);
+                }
+                _ => {
+                    return hv_result_err!(EINVAL, format!("Unknown config section {}", kind));
+                }
+            }
+        }
+
+        let (cpus, boot, arch) = match (cpus, boot, arch) {
+            (Some(cpus), Some(boot), Some(arch)) => (cpus, boot, arch),
+            _ => return hv_result_err!(EINVAL, "Zone config needs cpus, boot and arch"),
+        };
+        let mut config = Self::new(
+            header.zone_id,
+            cpus,
+            memory_regions,
+            interrupts,
+            boot.entry_point,
+            boot.kernel_load_paddr,
+            boot.dtb_load_paddr,
+            arch,
+        );
+        config.kernel_size = boot.kernel_size;
+        config.dtb_size = boot.dtb_size;
+        config.initrd_load_paddr = boot.initrd_load_paddr;
+        config.initrd_size = boot.initrd_size;
+        config.kernel_image = boot.kernel_image;
+        config.dtb_image = boot.dtb_image;
+        config.initrd_image = boot.initrd_image;
+        config.ivshmem = ivshmem;
+        config.channels = channels;
+        if let Some(watchdog) = watchdog {
+            config.watchdog = watchdog;
+        }
+        if let Some(sched) = sched {
+            config.time_slice_ms = sched.time_slice_ms;
+            config.major_frame_ms = sched.major_frame_ms;
         }
-        &self.memory_regions[..self.num_memory_regions as usize]
+        config.sched_windows = sched_windows;
+        config.guest_dtb = guest_dtb;
+        config.check_counts()?;
+        Ok(config)
     }
 
-    pub fn interrupts(&self) -> &[u32] {
-        if self.num_interrupts > CONFIG_MAX_INTERRUPTS as u32 {
-            panic!("Too many interrupts");
+    /// Read the zone config at `addr` in the memory of `zone`. A device tree
+    /// there holds the config in an `hvisor,zone` node and is the zone's own
+    /// device tree, unless the node gives a `dtb-image`.
+    pub fn read_guest(zone: &Zone, addr: GuestPhysAddr) -> HvResult<Self> {
+        let header: HvZoneConfigHeader = unsafe { zone.gpm.read_guest(addr)? };
+        let is_dtb = u32::from_be(header.magic) == FDT_MAGIC;
+        let size = if is_dtb {
+            // `totalsize` follows the magic of a device tree.
+            u32::from_be(header.version) as usize
+        } else {
+            header.size as usize
+        };
+        if size > CONFIG_MAX_SIZE {
+            return hv_result_err!(E2BIG, format!("Zone config of {:#x} bytes", size));
+        }
+        let mut bytes = vec![0; size];
+        zone.gpm.copy_from_guest(addr, &mut bytes)?;
+        if !is_dtb {
+            return Self::parse(&bytes);
+        }
+        let mut config = platform::dtb_zone_config(&bytes)?;
+        if config.dtb_image == 0 && config.guest_dtb.is_none() {
+            config.dtb_image = addr as _;
+            config.dtb_size = size as _;
+        }
+        Ok(config)
+    }
+
+    /// The config in the format `parse` reads.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let no_head: &[u8] = &[];
+        let mut buf = Vec::new();
+        push_bytes(
+            &mut buf,
+            &[HvZoneConfigHeader {
+                magic: ZONE_CONFIG_MAGIC,
+                version: ZONE_CONFIG_VERSION,
+                size: 0,
+                zone_id: self.zone_id,
+            }],
+        );
+        buf.resize(padded_size::<HvZoneConfigHeader>(), 0);
+        push_section(&mut buf, CONFIG_SECTION_CPUS, no_head, &[self.cpus]);
+        push_section(
+            &mut buf,
+            CONFIG_SECTION_MEMORY,
+            no_head,
+            &self.memory_regions,
+        );
+        push_section(&mut buf, CONFIG_SECTION_IRQS, no_head, &self.interrupts);
+        let boot = HvBootConfig {
+            entry_point: self.entry_point,
+            kernel_load_paddr: self.kernel_load_paddr,
+            kernel_size: self.kernel_size,
+            dtb_load_paddr: self.dtb_load_paddr,
+            dtb_size: self.dtb_size,
+            initrd_load_paddr: self.initrd_load_paddr,
+            initrd_size: self.initrd_size,
+            kernel_image: self.kernel_image,
+            dtb_image: self.dtb_image,
+            initrd_image: self.initrd_image,
+        };
+        push_section(&mut buf, CONFIG_SECTION_BOOT, no_head, &[boot]);
+        push_section(&mut buf, CONFIG_SECTION_ARCH, no_head, &[self.arch]);
+        push_section(&mut buf, CONFIG_SECTION_IVSHMEM, no_head, &self.ivshmem);
+        push_section(&mut buf, CONFIG_SECTION_CHANNELS, no_head, &self.channels);
+        push_section(&mut buf, CONFIG_SECTION_WATCHDOG, no_head, &[self.watchdog]);
+        let sched = HvSchedConfig {
+            time_slice_ms: self.time_slice_ms,
+            major_frame_ms: self.major_frame_ms,
+        };
+        push_section(
+            &mut buf,
+            CONFIG_SECTION_SCHED,
+            &[sched],
+            &self.sched_windows,
+        );
+        if let Some(guest_dtb) = &self.guest_dtb {
+            let mut payload = Vec::new();
+            push_bytes(
+                &mut payload,
+                &[HvGuestDtbHeader {
+                    num_virtio_irqs: guest_dtb.virtio_irqs.len() as _,
+                    bootargs_size: guest_dtb.bootargs.len() as _,
+                }],
+            );
+            push_bytes(&mut payload, &guest_dtb.virtio_irqs);
+            payload.extend_from_slice(guest_dtb.bootargs.as_bytes());
+            push_section(&mut buf, CONFIG_SECTION_GUEST_DTB, no_head, &payload);
         }
-        &self.interrupts[..self.num_interrupts as usize]
+        let size = buf.len() as u32;
+        buf[8..12].copy_from_slice(&size.to_ne_bytes());
+        buf
+    }
+
+    /// Check the number of entries of each section against the limits of
+    /// the hypervisor.
+    pub fn check_counts(&self) -> HvResult {
+        if self.memory_regions.len() > CONFIG_MAX_MEMORY_REGIONS {
+            return hv_result_err!(E2BIG, "Too many memory regions");
+        }
+        if self.interrupts.len() > CONFIG_MAX_INTERRUPTS {
+            return hv_result_err!(E2BIG, "Too many interrupts");
+        }
+        if self.ivshmem.len() > CONFIG_MAX_IVSHMEM {
+            return hv_result_err!(E2BIG, "Too many ivshmem regions");
+        }
+        if self.channels.len() > CONFIG_MAX_CHANNELS {
+            return hv_result_err!(E2BIG, "Too many channels");
+        }
+        if self.sched_windows.len() > CONFIG_MAX_SCHED_WINDOWS {
+            return hv_result_err!(E2BIG, "Too many schedule windows");
+        }
+        Ok(())
+    }
+
+    pub fn memory_regions(&self) -> &[HvConfigMemoryRegion] {
+        &self.memory_regions
+    }
+
+    pub fn interrupts(&self) -> &[u32] {
+        &self.interrupts
+    }
+
+    pub fn ivshmem(&self) -> &[HvIvshmemConfig] {
+        &self.ivshmem
+    }
+
+    pub fn channels(&self) -> &[HvChannelConfig] {
+        &self.channels
+    }
+
+    pub fn sched_windows(&self) -> &[HvSchedWindow] {
+        &self.sched_windows
+    }
+
+    /// Whether the zone may share its cpus with other zones.
+    pub fn time_shared(&self) -> bool {
+        self.time_slice_ms != 0 || !self.sched_windows.is_empty()
     }
 
     pub fn cpus(&self) -> Vec<u64> {
@@ -105,15 +655,25 @@ impl HvZoneConfig {
         }
         v
     }
+
+    pub fn set_cpus(&mut self, cpus: u64) {
+        self.cpus = cpus;
+    }
 }
 
-pub static mut HV_ROOT_ZONE_CONFIG: Once<HvZoneConfig> = Once::new();
+pub static mut HV_BOOT_ZONE_CONFIGS: Once<Vec<HvZoneConfig>> = Once::new();
 
-pub fn init() {
-    unsafe { HV_ROOT_ZONE_CONFIG.call_once(|| platform::platform_root_zone_config()) };
+/// Build the configs of the zones created at boot: the root zone, or the
+/// zones of a static partitioning, from the host device tree at `host_dtb`
+/// if it describes the board.
+pub fn init(host_dtb: usize) {
+    unsafe {
+        HV_BOOT_ZONE_CONFIGS.call_once(|| platform::platform_boot_zone_configs(host_dtb))
+    };
 }
 
-pub fn root_zone_config() -> &'static HvZoneConfig {
-    init();
-    unsafe { HV_ROOT_ZONE_CONFIG.get().unwrap() }
+/// Configs of the zones created at boot. The root zone comes alone, zones
+/// partitioned statically have non-zero ids.
+pub fn boot_zone_configs() -> &'static [HvZoneConfig] {
+    unsafe { HV_BOOT_ZONE_CONFIGS.get().unwrap() }
 }
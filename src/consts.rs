@@ -15,6 +15,10 @@ pub const INVALID_ADDRESS: usize = usize::MAX;
 
 pub const MAX_CPU_NUM: usize = 4;
 
+pub fn hv_start() -> VirtAddr {
+    skernel as _
+}
+
 pub fn core_end() -> VirtAddr {
     __core_end as _
 }
@@ -28,5 +32,6 @@ pub fn hv_end() -> VirtAddr {
 }
 
 extern "C" {
+    fn skernel();
     fn __core_end();
 }
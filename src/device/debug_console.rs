@@ -0,0 +1,60 @@
+//! Debug console shared by all zones through hypercalls.
+//!
+//! Output is buffered per zone and printed line by line on the hypervisor
+//! console, prefixed with the zone id, so that lines of different zones and
+//! of the hypervisor log do not interleave.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::device::uart;
+
+/// Lines longer than this are split.
+const LINE_MAX: usize = 256;
+/// Longest output accepted at once, so that a zone can't hold the console.
+pub const PUTS_MAX: usize = 4096;
+
+static LINE_BUFFERS: Mutex<BTreeMap<usize, Vec<u8>>> = Mutex::new(BTreeMap::new());
+
+fn flush_line(zone_id: usize, line: &mut Vec<u8>) {
+    println!("[zone {}] {}", zone_id, String::from_utf8_lossy(line));
+    line.clear();
+}
+
+/// Output `bytes` on behalf of zone `zone_id`.
+pub fn puts(zone_id: usize, bytes: &[u8]) {
+    let mut buffers = LINE_BUFFERS.lock();
+    let line = buffers.entry(zone_id).or_default();
+    for &c in bytes {
+        match c {
+            b'\n' => flush_line(zone_id, line),
+            b'\r' => {}
+            _ => {
+                line.push(c);
+                if line.len() >= LINE_MAX {
+                    flush_line(zone_id, line);
+                }
+            }
+        }
+    }
+}
+
+pub fn putc(zone_id: usize, c: u8) {
+    puts(zone_id, &[c]);
+}
+
+/// Read a character from the hypervisor console, if any is pending.
+pub fn getc() -> Option<u8> {
+    uart::console_getchar()
+}
+
+/// Print what is left of the output of zone `zone_id` and drop its buffer.
+pub fn flush(zone_id: usize) {
+    if let Some(mut line) = LINE_BUFFERS.lock().remove(&zone_id) {
+        if !line.is_empty() {
+            flush_line(zone_id, &mut line);
+        }
+    }
+}
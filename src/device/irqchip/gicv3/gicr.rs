@@ -4,7 +4,10 @@
 
 //! GICC Driver - GIC CPU interface.
 
-use crate::{arch::cpu::this_cpu_id, hypercall::SGI_IPI_ID};
+use crate::{
+    arch::{cpu::this_cpu_id, timer::HYP_TIMER_IRQ},
+    hypercall::SGI_IPI_ID,
+};
 
 use super::{
     gicd::{
@@ -34,6 +37,9 @@ pub const GICR_IPRIORITYR: usize = GICD_IPRIORITYR;
 pub const GICR_ICFGR: usize = GICD_ICFGR;
 pub const GICR_TYPER_LAST: usize = 1 << 4;
 
+/// Interrupts the hypervisor takes for itself, zones can't disable them.
+pub const GICR_HV_RESERVED_PPIS: u32 = 1 << HYP_TIMER_IRQ;
+
 pub fn enable_ipi() {
     let base = host_gicr_base(this_cpu_id()) + GICR_SGI_BASE;
 
@@ -60,3 +66,74 @@ pub fn enable_ipi() {
         }
     }
 }
+
+/// Enable the private interrupt `irq_id` of this cpu for the hypervisor.
+pub fn enable_hv_ppi(irq_id: usize) {
+    let base = host_gicr_base(this_cpu_id()) + GICR_SGI_BASE;
+
+    unsafe {
+        let gicr_igroupr0 = (base + GICR_IGROUPR) as *mut u32;
+        gicr_igroupr0.write_volatile(gicr_igroupr0.read_volatile() | (1 << irq_id));
+
+        let gicr_ipriorityr0 = (base + GICR_IPRIORITYR) as *mut u32;
+        {
+            let reg = irq_id / 4;
+            let offset = irq_id % 4 * 8;
+            let mask = ((1 << 8) - 1) << offset;
+            let p = gicr_ipriorityr0.add(reg as _);
+            let prio = p.read_volatile();
+
+            p.write_volatile((prio & !mask) | (0x01 << offset));
+        }
+
+        let gicr_isenabler0 = (base + GICR_ISENABLER) as *mut u32;
+        gicr_isenabler0.write_volatile(1 << irq_id);
+    }
+}
+
+/// PPIs of this cpu left to the zone running on it.
+const GICR_ZONE_PPIS: u32 = 0xffff_0000 & !GICR_HV_RESERVED_PPIS;
+
+/// Byte mask of the priorities of zone PPIs in `GICR_IPRIORITYR<reg>`.
+fn zone_ppi_priority_mask(reg: usize) -> u32 {
+    (0..4)
+        .filter(|byte| GICR_ZONE_PPIS & (1 << (reg * 4 + byte)) != 0)
+        .fold(0, |mask, byte| mask | 0xff << (byte * 8))
+}
+
+/// Byte mask of the priorities of the hypervisor PPIs in an access of `size`
+/// bytes at byte `offset` of `GICR_IPRIORITYR`.
+pub fn hv_ppi_priority_mask(offset: usize, size: usize) -> usize {
+    (0..size)
+        .filter(|byte| GICR_HV_RESERVED_PPIS as u64 & (1 << (offset + byte)) != 0)
+        .fold(0, |mask, byte| mask | 0xff << (byte * 8))
+}
+
+/// Enable bits and priorities of the zone PPIs of this cpu. The priorities
+/// of PPIs 16..31 are in `GICR_IPRIORITYR4..7`.
+pub fn save_zone_ppis() -> (u32, [u32; 4]) {
+    let base = host_gicr_base(this_cpu_id()) + GICR_SGI_BASE;
+    let mut priority = [0; 4];
+    unsafe {
+        let enable = ((base + GICR_ISENABLER) as *const u32).read_volatile() & GICR_ZONE_PPIS;
+        for (i, prio) in priority.iter_mut().enumerate() {
+            let p = (base + GICR_IPRIORITYR + (i + 4) * 4) as *const u32;
+            *prio = p.read_volatile() & zone_ppi_priority_mask(i + 4);
+        }
+        (enable, priority)
+    }
+}
+
+/// Set the zone PPIs of this cpu as saved by `save_zone_ppis`.
+pub fn restore_zone_ppis(enable: u32, priority: &[u32; 4]) {
+    let base = host_gicr_base(this_cpu_id()) + GICR_SGI_BASE;
+    unsafe {
+        for (i, &prio) in priority.iter().enumerate() {
+            let mask = zone_ppi_priority_mask(i + 4);
+            let p = (base + GICR_IPRIORITYR + (i + 4) * 4) as *mut u32;
+            p.write_volatile((p.read_volatile() & !mask) | (prio & mask));
+        }
+        ((base + GICR_ICENABLER) as *mut u32).write_volatile(GICR_ZONE_PPIS & !enable);
+        ((base + GICR_ISENABLER) as *mut u32).write_volatile(GICR_ZONE_PPIS & enable);
+    }
+}
@@ -81,20 +81,27 @@ pub mod gicr;
 pub mod vgic;
 
 use core::arch::asm;
-use core::ptr::write_volatile;
+use core::ptr::{read_volatile, write_volatile};
 use core::sync::atomic::AtomicU64;
 
 use spin::Once;
 
-use self::gicd::{enable_gic_are_ns, GICD_ICACTIVER, GICD_ICENABLER};
-use self::gicr::enable_ipi;
+use self::gicd::{
+    enable_gic_are_ns, GICD_ICACTIVER, GICD_ICENABLER, GICD_ICFGR, GICD_IPRIORITYR, GICD_IROUTER,
+    GICD_ISENABLER,
+};
+use self::gicr::{enable_hv_ppi, enable_ipi};
+use crate::arch::aarch64::cpu::mpidr_to_cpuid;
 use crate::arch::aarch64::sysreg::{read_sysreg, smc_arg1, write_sysreg};
-use crate::config::root_zone_config;
+use crate::arch::aarch64::timer::{handle_hyp_timer, HYP_TIMER_IRQ};
+use crate::config::boot_zone_configs;
 use crate::consts::MAX_CPU_NUM;
 
 use crate::event::check_events;
 use crate::hypercall::SGI_IPI_ID;
+use crate::sched;
 use crate::zone::Zone;
+use alloc::vec::Vec;
 
 //TODO: add Distributor init
 pub fn gicc_init() {
@@ -120,7 +127,7 @@ pub fn gicc_init() {
     info!("gicc init done, sdei_ver = {}", sdei_ver);
 }
 
-fn gicv3_clear_pending_irqs() {
+pub fn gicv3_clear_pending_irqs() {
     let vtr = read_sysreg!(ich_vtr_el2) as usize;
     let lr_num: usize = (vtr & 0xf) + 1;
     for i in 0..lr_num {
@@ -180,6 +187,10 @@ pub fn gicv3_handle_irq_el1() {
         } else if irq_id < 16 {
             warn!("skip sgi {}", irq_id);
             deactivate_irq(irq_id);
+        } else if irq_id == HYP_TIMER_IRQ {
+            deactivate_irq(irq_id);
+            write_sysreg!(icc_dir_el1, irq_id as u64);
+            handle_hyp_timer();
         } else {
             if irq_id == 27 {
                 // virtual timer interrupt
@@ -194,7 +205,7 @@ pub fn gicv3_handle_irq_el1() {
                 debug!("*** get spi_irq id = {}", irq_id);
             }
             deactivate_irq(irq_id);
-            inject_irq(irq_id, true);
+            sched::inject_spi(irq_id);
         }
     }
     trace!("handle done")
@@ -218,7 +229,7 @@ fn deactivate_irq(irq_id: usize) {
     //write_sysreg!(icc_dir_el1, irq_id as usize);
 }
 
-fn read_lr(id: usize) -> u64 {
+pub fn read_lr(id: usize) -> u64 {
     let id = id as u64;
     match id {
         //TODO get lr size from gic reg
@@ -245,7 +256,7 @@ fn read_lr(id: usize) -> u64 {
     }
 }
 
-fn write_lr(id: usize, val: u64) {
+pub fn write_lr(id: usize, val: u64) {
     let id = id as u64;
     match id {
         0 => write_sysreg!(ich_lr0_el2, val),
@@ -339,6 +350,15 @@ pub fn host_gicr_size() -> usize {
     GIC.get().unwrap().gicr_size
 }
 
+/// Whether `start..start + size` overlaps the distributor or redistributors,
+/// which zones only reach through the vGIC.
+pub fn overlaps_gic(start: usize, size: usize) -> bool {
+    let gic = GIC.get().unwrap();
+    [(gic.gicd_base, gic.gicd_size), (gic.gicr_base, gic.gicr_size)]
+        .iter()
+        .any(|&(base, gic_size)| start < base + gic_size && base < start + size)
+}
+
 pub fn is_spi(irqn: u32) -> bool {
     irqn > 31 && irqn < 1020
 }
@@ -356,13 +376,14 @@ pub fn disable_irqs() {
 }
 
 pub fn primary_init_early() {
-    let root_config = root_zone_config();
-    
+    // All zones describe the same physical GIC.
+    let arch = &boot_zone_configs()[0].arch;
+
     GIC.call_once(|| Gic {
-        gicd_base: root_config.arch.gicd_base,
-        gicr_base: root_config.arch.gicr_base,
-        gicd_size: root_config.arch.gicd_size,
-        gicr_size: root_config.arch.gicr_size,
+        gicd_base: arch.gicd_base,
+        gicr_base: arch.gicr_base,
+        gicd_size: arch.gicd_size,
+        gicr_size: arch.gicr_size,
     });
     debug!("gic = {:#x?}", GIC.get().unwrap());
 }
@@ -375,6 +396,7 @@ pub fn primary_init_late() {
 pub fn percpu_init() {
     gicc_init();
     enable_ipi();
+    enable_hv_ppi(HYP_TIMER_IRQ);
 }
 
 impl Zone {
@@ -390,4 +412,78 @@ impl Zone {
             }
         }
     }
+
+    /// Distributor state of the SPIs of this zone.
+    pub fn arch_irqchip_save(&self) -> Vec<HvIrqState> {
+        let gicd_base = host_gicd_base();
+        (32..1024)
+            .filter(|&irq| self.irq_in_zone(irq as _))
+            .map(|irq| unsafe {
+                HvIrqState {
+                    irq: irq as _,
+                    enabled: read_field(gicd_base + GICD_ISENABLER, irq, 1),
+                    priority: read_field(gicd_base + GICD_IPRIORITYR, irq, 8),
+                    config: read_field(gicd_base + GICD_ICFGR, irq, 2),
+                    route: read_volatile((gicd_base + GICD_IROUTER + irq * 8) as *const u64),
+                }
+            })
+            .collect()
+    }
+
+    /// Program the SPIs of this zone as saved by `arch_irqchip_save`. The
+    /// states come from the root zone: irqs of other zones are skipped, and
+    /// routes to cpus outside this zone go to its first cpu instead.
+    pub fn arch_irqchip_restore(&self, irqs: &[HvIrqState]) {
+        let gicd_base = host_gicd_base();
+        let first_cpu = self.cpu_set.first_cpu().unwrap() as u64;
+        for state in irqs
+            .iter()
+            .filter(|state| is_spi(state.irq) && self.irq_in_zone(state.irq))
+        {
+            let irq = state.irq as usize;
+            let route = match mpidr_to_cpuid(state.route) {
+                cpu_id if state.route & IROUTER_IRM == 0 && self.owns_cpu(cpu_id as _) => cpu_id,
+                _ => first_cpu,
+            };
+            unsafe {
+                write_field(gicd_base + GICD_IPRIORITYR, irq, 8, state.priority);
+                write_field(gicd_base + GICD_ICFGR, irq, 2, state.config);
+                write_volatile((gicd_base + GICD_IROUTER + irq * 8) as *mut u64, route);
+                if state.enabled != 0 {
+                    write_volatile(
+                        (gicd_base + GICD_ISENABLER + irq / 32 * 4) as *mut u32,
+                        1 << (irq % 32),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Interrupt_Routing_Mode of `GICD_IROUTER<n>`, routing to any cpu.
+const IROUTER_IRM: u64 = 1 << 31;
+
+/// Distributor state of an SPI, see `Zone::arch_irqchip_save`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HvIrqState {
+    pub irq: u32,
+    pub enabled: u32,
+    pub priority: u32,
+    pub config: u32,
+    pub route: u64,
+}
+
+/// Read the `bits` wide field of `irq` in the register array at `base`.
+unsafe fn read_field(base: usize, irq: usize, bits: usize) -> u32 {
+    let reg = read_volatile((base + irq * bits / 32 * 4) as *const u32);
+    (reg >> (irq * bits % 32)) & ((1u64 << bits) - 1) as u32
+}
+
+/// Write the `bits` wide field of `irq` in the register array at `base`.
+unsafe fn write_field(base: usize, irq: usize, bits: usize, val: u32) {
+    let p = (base + irq * bits / 32 * 4) as *mut u32;
+    let shift = irq * bits % 32;
+    let mask = (((1u64 << bits) - 1) as u32) << shift;
+    p.write_volatile((p.read_volatile() & !mask) | ((val << shift) & mask));
 }
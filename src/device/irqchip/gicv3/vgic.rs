@@ -124,6 +124,38 @@ pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
         GICR_SYNCR => {
             mmio.value = 0;
         }
+        reg if mmio.is_write
+            && (reg == GICR_SGI_BASE + GICR_ICENABLER
+                || reg == GICR_SGI_BASE + GICR_ICACTIVER
+                || reg == GICR_SGI_BASE + GICR_ICPENDR) =>
+        {
+            // Keep the interrupts of the hypervisor enabled.
+            mmio.value &= !(GICR_HV_RESERVED_PPIS as usize);
+            if Arc::ptr_eq(&this_zone(), get_cpu_data(cpu).zone.as_ref().unwrap()) {
+                mmio_perform_access(gicr_base, mmio);
+            }
+        }
+        reg if mmio.is_write
+            && (reg == GICR_SGI_BASE + GICR_IGROUPR
+                || (GICR_SGI_BASE + GICR_IPRIORITYR..GICR_SGI_BASE + GICR_IPRIORITYR + 32)
+                    .contains(&reg)) =>
+        {
+            // Keep the group and priority of the interrupts of the hypervisor,
+            // they must stay visible to it.
+            let reserved = if reg == GICR_SGI_BASE + GICR_IGROUPR {
+                GICR_HV_RESERVED_PPIS as usize
+            } else {
+                hv_ppi_priority_mask(reg - GICR_SGI_BASE - GICR_IPRIORITYR, mmio.size)
+            };
+            if Arc::ptr_eq(&this_zone(), get_cpu_data(cpu).zone.as_ref().unwrap()) {
+                let access_val = mmio.value;
+                mmio.is_write = false;
+                mmio_perform_access(gicr_base, mmio);
+                mmio.is_write = true;
+                mmio.value = (mmio.value & reserved) | (access_val & !reserved);
+                mmio_perform_access(gicr_base, mmio);
+            }
+        }
         _ => {
             if Arc::ptr_eq(&this_zone(), get_cpu_data(cpu).zone.as_ref().unwrap()) {
                 // ignore access to foreign redistributors
@@ -0,0 +1,38 @@
+//! Doorbell of the memory shared between zones.
+//!
+//! Every zone sharing a `MEM_TYPE_IVSHMEM` region gets an emulated doorbell
+//! page. Reading `IVSHMEM_REG_ID` returns the zone's own peer id, writing a
+//! peer id to `IVSHMEM_REG_DOORBELL` injects the irq configured for that peer.
+
+use crate::error::HvResult;
+use crate::event::send_virq;
+use crate::memory::MMIOAccess;
+use crate::percpu::this_zone;
+use crate::zone::find_ivshmem_peer;
+
+pub const IVSHMEM_REG_ID: usize = 0x0;
+pub const IVSHMEM_REG_DOORBELL: usize = 0x4;
+
+/// Doorbell handler, `physical_start` is the start of the shared memory.
+pub fn mmio_ivshmem_handler(mmio: &mut MMIOAccess, physical_start: usize) -> HvResult {
+    let peer_id = match this_zone()
+        .read()
+        .ivshmem
+        .iter()
+        .find(|ivshmem| ivshmem.physical_start as usize == physical_start)
+    {
+        Some(ivshmem) => ivshmem.peer_id,
+        None => return hv_result_err!(ENODEV),
+    };
+
+    match (mmio.address, mmio.is_write) {
+        (IVSHMEM_REG_ID, false) => mmio.value = peer_id as _,
+        (IVSHMEM_REG_DOORBELL, true) => match find_ivshmem_peer(physical_start, mmio.value as _) {
+            Some((cpu_id, zone_id, irq_id)) => send_virq(cpu_id, zone_id, irq_id as _),
+            None => warn!("ivshmem {:#x}: no peer {}", physical_start, mmio.value),
+        },
+        (_, false) => mmio.value = 0,
+        _ => {}
+    }
+    Ok(())
+}
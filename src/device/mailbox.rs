@@ -0,0 +1,113 @@
+//! Mailbox channels between zones.
+//!
+//! A channel connects the two zones whose configs declare the same name. Each
+//! end owns a ring of fixed size messages kept by the hypervisor: sending
+//! appends to the ring of the peer and injects the irq it configured, the peer
+//! then fetches the messages one by one.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::config::{HvChannelConfig, CONFIG_CHANNEL_NAME_LEN};
+use crate::error::HvResult;
+use crate::event::send_virq;
+use crate::zone::find_zone;
+
+pub const MAILBOX_MSG_SIZE: usize = 64;
+/// Messages a channel end holds before senders get `EBUSY`.
+pub const MAILBOX_RING_SIZE: usize = 16;
+
+pub type MailboxMsg = [u8; MAILBOX_MSG_SIZE];
+
+struct Channel {
+    name: [u8; CONFIG_CHANNEL_NAME_LEN],
+    irq: u32,
+    opened: bool,
+    ring: VecDeque<MailboxMsg>,
+}
+
+/// Channels of every zone, indexed by zone id then by channel handle.
+static CHANNELS: Mutex<BTreeMap<usize, Vec<Channel>>> = Mutex::new(BTreeMap::new());
+
+/// Set up the channels declared by zone `zone_id`, closed until it opens them.
+pub fn register(zone_id: usize, configs: &[HvChannelConfig]) {
+    let channels = configs
+        .iter()
+        .map(|config| Channel {
+            name: config.name,
+            irq: config.irq,
+            opened: false,
+            ring: VecDeque::new(),
+        })
+        .collect();
+    CHANNELS.lock().insert(zone_id, channels);
+}
+
+/// Drop the channels of zone `zone_id` along with the pending messages.
+pub fn unregister(zone_id: usize) {
+    CHANNELS.lock().remove(&zone_id);
+}
+
+/// Open the channel `name` of zone `zone_id` and return its handle.
+pub fn open(zone_id: usize, name: &[u8; CONFIG_CHANNEL_NAME_LEN]) -> HvResult<usize> {
+    let mut all_channels = CHANNELS.lock();
+    let channels = all_channels.get_mut(&zone_id).ok_or(hv_err!(ENOENT))?;
+    match channels.iter().position(|channel| &channel.name == name) {
+        Some(handle) => {
+            channels[handle].opened = true;
+            Ok(handle)
+        }
+        None => hv_result_err!(ENOENT, "Channel not declared in the zone config"),
+    }
+}
+
+fn opened_channel(
+    all_channels: &mut BTreeMap<usize, Vec<Channel>>,
+    zone_id: usize,
+    handle: usize,
+) -> HvResult<&mut Channel> {
+    match all_channels
+        .get_mut(&zone_id)
+        .and_then(|channels| channels.get_mut(handle))
+    {
+        Some(channel) if channel.opened => Ok(channel),
+        _ => hv_result_err!(EINVAL, format!("Invalid channel handle {}", handle)),
+    }
+}
+
+/// Send `msg` from zone `zone_id` on channel `handle`.
+pub fn send(zone_id: usize, handle: usize, msg: &MailboxMsg) -> HvResult {
+    let mut all_channels = CHANNELS.lock();
+    let name = opened_channel(&mut all_channels, zone_id, handle)?.name;
+    let (peer_id, peer) = all_channels
+        .iter_mut()
+        .filter(|(&id, _)| id != zone_id)
+        .find_map(|(&id, channels)| {
+            channels
+                .iter_mut()
+                .find(|channel| channel.opened && channel.name == name)
+                .map(|channel| (id, channel))
+        })
+        .ok_or(hv_err!(ENOENT, "Peer has not opened the channel"))?;
+    if peer.ring.len() >= MAILBOX_RING_SIZE {
+        return hv_result_err!(EBUSY);
+    }
+    peer.ring.push_back(*msg);
+    let irq = peer.irq;
+    drop(all_channels);
+
+    // Without an online cpu the message waits for the peer to poll.
+    let cpu_id = find_zone(peer_id).and_then(|zone| zone.read().first_online_cpu());
+    if let Some(cpu_id) = cpu_id {
+        send_virq(cpu_id, peer_id, irq as _);
+    }
+    Ok(())
+}
+
+/// Fetch the oldest message for zone `zone_id` on channel `handle`.
+pub fn recv(zone_id: usize, handle: usize) -> HvResult<Option<MailboxMsg>> {
+    let mut all_channels = CHANNELS.lock();
+    let channel = opened_channel(&mut all_channels, zone_id, handle)?;
+    Ok(channel.ring.pop_front())
+}
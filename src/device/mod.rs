@@ -1,4 +1,7 @@
 pub mod common;
+pub mod debug_console;
 pub mod irqchip;
+pub mod ivshmem;
+pub mod mailbox;
 pub mod uart;
 pub mod virtio_trampoline;
@@ -61,7 +61,7 @@ pub fn mmio_virtio_handler(mmio: &mut MMIOAccess, base: usize) -> HvResult {
     dev.push_req(hreq);
     // If req list is empty, send sgi to root linux to wake up virtio device.
     if dev.need_wakeup() {
-        let root_cpu = root_zone().read().cpu_set.first_cpu().unwrap();
+        let root_cpu = root_zone().unwrap().read().cpu_set.first_cpu().unwrap();
         send_event(root_cpu, SGI_IPI_ID as _, IPI_EVENT_WAKEUP_VIRTIO_DEVICE);
     }
     drop(dev);
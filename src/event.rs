@@ -4,7 +4,9 @@ use crate::{
         irqchip::gicv3::inject_irq,
         virtio_trampoline::{handle_virtio_irq, IRQ_WAKEUP_VIRTIO_DEVICE},
     },
+    hypercall::SGI_IPI_ID,
     percpu::this_cpu_data,
+    sched, watchdog,
 };
 use alloc::{collections::VecDeque, vec::Vec};
 use spin::{Mutex, Once};
@@ -13,20 +15,29 @@ pub const IPI_EVENT_WAKEUP: usize = 0;
 pub const IPI_EVENT_SHUTDOWN: usize = 1;
 pub const IPI_EVENT_VIRTIO_INJECT_IRQ: usize = 2;
 pub const IPI_EVENT_WAKEUP_VIRTIO_DEVICE: usize = 3;
+pub const IPI_EVENT_SUSPEND: usize = 4;
+pub const IPI_EVENT_REBOOT: usize = 5;
+pub const IPI_EVENT_INJECT_VIRQ: usize = 6;
+pub const IPI_EVENT_SCHEDULE: usize = 7;
+pub const IPI_EVENT_WATCHDOG: usize = 8;
 static EVENT_MANAGER: Once<EventManager> = Once::new();
 
 struct EventManager {
     pub inner: Vec<Mutex<VecDeque<usize>>>,
+    /// Virtual irqs waiting for `IPI_EVENT_INJECT_VIRQ`, `(zone_id, irq_id)`.
+    pub virqs: Vec<Mutex<VecDeque<(usize, usize)>>>,
 }
 
 impl EventManager {
     fn new(max_cpus: usize) -> Self {
         let mut vs = vec![];
+        let mut virqs = vec![];
         for _ in 0..max_cpus {
             let v = Mutex::new(VecDeque::new());
-            vs.push(v)
+            vs.push(v);
+            virqs.push(Mutex::new(VecDeque::new()));
         }
-        Self { inner: vs }
+        Self { inner: vs, virqs }
     }
 
     fn add_event(&self, cpu: usize, event_id: usize) -> Option<()> {
@@ -80,6 +91,28 @@ pub fn check_events() -> bool {
             inject_irq(IRQ_WAKEUP_VIRTIO_DEVICE, false);
             true
         }
+        Some(IPI_EVENT_REBOOT) => {
+            cpu_data.run_vm();
+        }
+        Some(IPI_EVENT_INJECT_VIRQ) => {
+            let virqs = &EVENT_MANAGER.get().unwrap().virqs[cpu_data.id];
+            while let Some((zone_id, irq_id)) = virqs.lock().pop_front() {
+                sched::inject_virq(zone_id, irq_id);
+            }
+            true
+        }
+        Some(IPI_EVENT_SCHEDULE) => {
+            sched::schedule();
+            true
+        }
+        Some(IPI_EVENT_SUSPEND) => {
+            sched::suspend_current();
+            true
+        }
+        Some(IPI_EVENT_WATCHDOG) => {
+            watchdog::handle_event();
+            true
+        }
         _ => false,
     }
 }
@@ -88,3 +121,11 @@ pub fn send_event(cpu_id: usize, ipi_int_id: usize, event_id: usize) {
     add_event(cpu_id, event_id);
     arch_send_event(cpu_id as _, ipi_int_id as _);
 }
+
+/// Inject the virtual irq `irq_id` into zone `zone_id` on `cpu_id`.
+pub fn send_virq(cpu_id: usize, zone_id: usize, irq_id: usize) {
+    EVENT_MANAGER.get().unwrap().virqs[cpu_id]
+        .lock()
+        .push_back((zone_id, irq_id));
+    send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_INJECT_VIRQ);
+}
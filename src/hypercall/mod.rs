@@ -1,13 +1,29 @@
 #![allow(dead_code)]
-use crate::config::HvZoneConfig;
-use crate::consts::{INVALID_ADDRESS, PAGE_SIZE};
-use crate::device::virtio_trampoline::{VIRTIO_BRIDGE, MAX_DEVS, MAX_REQ, VIRTIO_IRQS};
+use crate::checkpoint;
+use crate::config::{
+    HvZoneConfig, CONFIG_CHANNEL_NAME_LEN, CONFIG_MAX_INTERRUPTS, CONFIG_MAX_MEMORY_REGIONS,
+};
+use crate::consts::{MAX_CPU_NUM, PAGE_SIZE};
+use crate::device::debug_console;
+use crate::device::mailbox::{self, MailboxMsg, MAILBOX_MSG_SIZE};
+use crate::device::virtio_trampoline::{
+    VirtioBridge, MAX_DEVS, MAX_REQ, VIRTIO_BRIDGE, VIRTIO_IRQS,
+};
 use crate::error::HvResult;
-use crate::percpu::{get_cpu_data, PerCpu};
-use crate::zone::{find_zone, is_this_root_zone, remove_zone, zone_create};
+use crate::memory::addr::phys_to_virt;
+use crate::memory::MemFlags;
+use crate::percpu::{this_zone, PerCpu};
+use crate::sched;
+use crate::stats::{cpu_stats, STATS_ENABLED};
+use crate::watchdog;
+use crate::zone::{
+    find_zone, is_this_console_zone, is_this_root_zone, this_zone_id, zone_add_cpu, zone_create,
+    zone_list_info, zone_remove_cpu, zone_shutdown, HvZoneInfo,
+};
 
-use crate::event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_VIRTIO_INJECT_IRQ, IPI_EVENT_WAKEUP};
+use crate::event::{send_event, IPI_EVENT_VIRTIO_INJECT_IRQ};
 use core::convert::TryFrom;
+use core::mem::size_of;
 use core::sync::atomic::{fence, Ordering};
 
 use numeric_enum_macro::numeric_enum;
@@ -20,10 +36,124 @@ numeric_enum! {
         HvVirtioInjectIrq = 1,
         HvZoneStart = 2,
         HvZoneShutdown = 3,
+        HvZoneList = 4,
+        HvZonePause = 5,
+        HvZoneResume = 6,
+        HvZoneReboot = 7,
+        HvGetInfo = 8,
+        HvDebugConsolePutc = 9,
+        HvDebugConsolePuts = 10,
+        HvDebugConsoleGetc = 11,
+        HvZoneAddCpu = 12,
+        HvZoneRemoveCpu = 13,
+        HvGetCpuStats = 14,
+        HvGetZoneStats = 15,
+        HvChannelOpen = 16,
+        HvChannelSend = 17,
+        HvChannelRecv = 18,
+        HvWatchdogHeartbeat = 19,
+        HvGetSchedInfo = 20,
+        HvZoneCheckpoint = 21,
+        HvZoneRestore = 22,
     }
 }
 pub const SGI_IPI_ID: u64 = 7;
 
+/// Version of the hypercall ABI, bumped on incompatible changes.
+pub const HV_ABI_VERSION: u32 = 11;
+
+/// Virtio devices backed by the root zone.
+pub const HV_FEATURE_VIRTIO: u64 = 1 << 0;
+/// Zone list, pause, resume and reboot hypercalls.
+pub const HV_FEATURE_ZONE_CONTROL: u64 = 1 << 1;
+/// RAM of removed zones is scrubbed before it is given back.
+pub const HV_FEATURE_MEMORY_SCRUB: u64 = 1 << 2;
+/// Zone images are copied by the hypervisor, see `HvZoneConfig::kernel_image`.
+pub const HV_FEATURE_IMAGE_LOAD: u64 = 1 << 3;
+/// Debug console hypercalls.
+pub const HV_FEATURE_DEBUG_CONSOLE: u64 = 1 << 4;
+/// Cpus can be added to and removed from running zones.
+pub const HV_FEATURE_CPU_HOTPLUG: u64 = 1 << 5;
+/// VM exit statistics, only with `STATS=on`.
+pub const HV_FEATURE_STATS: u64 = 1 << 6;
+/// Memory shared between zones with doorbell interrupts.
+pub const HV_FEATURE_IVSHMEM: u64 = 1 << 7;
+/// Mailbox channels between zones.
+pub const HV_FEATURE_MAILBOX: u64 = 1 << 8;
+/// Zone watchdogs and the heartbeat hypercall.
+pub const HV_FEATURE_WATCHDOG: u64 = 1 << 9;
+/// Zones with a time slice share cpus, see `HvZoneConfig::time_slice_ms`.
+pub const HV_FEATURE_TIME_SHARING: u64 = 1 << 10;
+/// Cyclic schedule windows and the schedule info hypercall.
+pub const HV_FEATURE_CYCLIC_SCHED: u64 = 1 << 11;
+/// Zone checkpoint and restore hypercalls.
+pub const HV_FEATURE_CHECKPOINT: u64 = 1 << 12;
+/// `HvConfigMemoryRegion::flags` set the permissions and memory type.
+pub const HV_FEATURE_MEM_FLAGS: u64 = 1 << 13;
+/// Zones are started with a device tree holding an `hvisor,zone` node.
+pub const HV_FEATURE_DTB_CONFIG: u64 = 1 << 14;
+/// The hypervisor generates device trees of zones, see `HvGuestDtbHeader`.
+pub const HV_FEATURE_GUEST_DTB: u64 = 1 << 15;
+
+pub const HV_FEATURES: u64 = HV_FEATURE_VIRTIO
+    | HV_FEATURE_ZONE_CONTROL
+    | HV_FEATURE_MEMORY_SCRUB
+    | HV_FEATURE_IMAGE_LOAD
+    | HV_FEATURE_DEBUG_CONSOLE
+    | HV_FEATURE_CPU_HOTPLUG
+    | HV_FEATURE_IVSHMEM
+    | HV_FEATURE_MAILBOX
+    | HV_FEATURE_WATCHDOG
+    | HV_FEATURE_TIME_SHARING
+    | HV_FEATURE_CYCLIC_SCHED
+    | HV_FEATURE_CHECKPOINT
+    | HV_FEATURE_MEM_FLAGS
+    | if cfg!(target_arch = "aarch64") {
+        HV_FEATURE_DTB_CONFIG | HV_FEATURE_GUEST_DTB
+    } else {
+        0
+    }
+    | if STATS_ENABLED { HV_FEATURE_STATS } else { 0 };
+
+/// Hypervisor build information, see `HyperCallCode::HvGetInfo`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvInfo {
+    pub abi_version: u32,
+    pub max_cpus: u32,
+    pub max_memory_regions: u32,
+    pub max_interrupts: u32,
+    pub features: u64,
+    pub mode: [u8; 16],
+    pub arch: [u8; 16],
+    pub vendor: [u8; 16],
+}
+
+/// Copy `s` into a NUL padded array, truncating it if needed.
+const fn build_str(s: Option<&str>) -> [u8; 16] {
+    let mut buf = [0; 16];
+    if let Some(s) = s {
+        let bytes = s.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() && i < buf.len() - 1 {
+            buf[i] = bytes[i];
+            i += 1;
+        }
+    }
+    buf
+}
+
+pub const HV_INFO: HvInfo = HvInfo {
+    abi_version: HV_ABI_VERSION,
+    max_cpus: MAX_CPU_NUM as _,
+    max_memory_regions: CONFIG_MAX_MEMORY_REGIONS as _,
+    max_interrupts: CONFIG_MAX_INTERRUPTS as _,
+    features: HV_FEATURES,
+    mode: build_str(option_env!("MODE")),
+    arch: build_str(option_env!("ARCH")),
+    vendor: build_str(option_env!("VENDOR")),
+};
+
 pub type HyperCallResult = HvResult<usize>;
 
 pub struct HyperCall<'a> {
@@ -35,20 +165,39 @@ impl<'a> HyperCall<'a> {
         Self { cpu_data }
     }
 
-    pub fn hypercall(&mut self, code: u64, arg0: u64, _arg1: u64) -> HyperCallResult {
+    pub fn hypercall(&mut self, code: u64, arg0: u64, arg1: u64) -> HyperCallResult {
         let code = match HyperCallCode::try_from(code) {
             Ok(code) => code,
             Err(_) => {
                 warn!("hypercall id={} unsupported!", code);
-                return Ok(0);
+                return hv_result_err!(ENOSYS);
             }
         };
         unsafe {
             match code {
                 HyperCallCode::HvVirtioInit => self.hv_virtio_init(arg0),
                 HyperCallCode::HvVirtioInjectIrq => self.hv_virtio_inject_irq(),
-                HyperCallCode::HvZoneStart => self.hv_zone_start(&*(arg0 as *const HvZoneConfig)),
+                HyperCallCode::HvZoneStart => self.hv_zone_start(arg0),
                 HyperCallCode::HvZoneShutdown => self.hv_zone_shutdown(arg0),
+                HyperCallCode::HvZoneList => self.hv_zone_list(arg0, arg1),
+                HyperCallCode::HvZonePause => self.hv_zone_pause(arg0),
+                HyperCallCode::HvZoneResume => self.hv_zone_resume(arg0),
+                HyperCallCode::HvZoneReboot => self.hv_zone_reboot(arg0),
+                HyperCallCode::HvGetInfo => self.hv_get_info(arg0),
+                HyperCallCode::HvDebugConsolePutc => self.hv_debug_console_putc(arg0),
+                HyperCallCode::HvDebugConsolePuts => self.hv_debug_console_puts(arg0, arg1),
+                HyperCallCode::HvDebugConsoleGetc => self.hv_debug_console_getc(),
+                HyperCallCode::HvZoneAddCpu => self.hv_zone_add_cpu(arg0, arg1),
+                HyperCallCode::HvZoneRemoveCpu => self.hv_zone_remove_cpu(arg0, arg1),
+                HyperCallCode::HvGetCpuStats => self.hv_get_cpu_stats(arg0, arg1),
+                HyperCallCode::HvGetZoneStats => self.hv_get_zone_stats(arg0, arg1),
+                HyperCallCode::HvChannelOpen => self.hv_channel_open(arg0),
+                HyperCallCode::HvChannelSend => self.hv_channel_send(arg0, arg1),
+                HyperCallCode::HvChannelRecv => self.hv_channel_recv(arg0, arg1),
+                HyperCallCode::HvWatchdogHeartbeat => self.hv_watchdog_heartbeat(),
+                HyperCallCode::HvGetSchedInfo => self.hv_get_sched_info(arg0, arg1),
+                HyperCallCode::HvZoneCheckpoint => self.hv_zone_checkpoint(arg0, arg1),
+                HyperCallCode::HvZoneRestore => self.hv_zone_restore(arg0),
             }
         }
     }
@@ -62,8 +211,14 @@ impl<'a> HyperCall<'a> {
         if !is_this_root_zone() {
             return hv_result_err!(EPERM, "Init virtio over non-root zones: unsupported!");
         }
-        let shared_region_addr_pa = shared_region_addr as usize;
-        assert!(shared_region_addr_pa % PAGE_SIZE == 0);
+        if shared_region_addr as usize % PAGE_SIZE != 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let shared_region_addr_pa = this_zone().read().gpm.translate_guest_range(
+            shared_region_addr as _,
+            size_of::<VirtioBridge>(),
+            MemFlags::READ | MemFlags::WRITE,
+        )?;
         // let offset = shared_region_addr_pa & (PAGE_SIZE - 1);
         // memory::hv_page_table()
         // 	.write()
@@ -76,7 +231,7 @@ impl<'a> HyperCall<'a> {
         // TODO: flush tlb
         VIRTIO_BRIDGE
             .lock()
-            .set_base_addr(shared_region_addr_pa as _);
+            .set_base_addr(phys_to_virt(shared_region_addr_pa));
         info!("hvisor device region base is {:#x?}", shared_region_addr_pa);
         HyperCallResult::Ok(0)
     }
@@ -124,27 +279,25 @@ impl<'a> HyperCall<'a> {
         HyperCallResult::Ok(0)
     }
 
-    pub fn hv_zone_start(&mut self, config: &HvZoneConfig) -> HyperCallResult {
-        info!("hv_zone_start: config: {:#x?}", config);
+    pub fn hv_zone_start(&mut self, config_addr: u64) -> HyperCallResult {
         if !is_this_root_zone() {
             return hv_result_err!(
                 EPERM,
                 "Start zone operation over non-root zones: unsupported!"
             );
         }
-        let zone = zone_create(config)?;
-        let boot_cpu = zone.read().cpu_set.first_cpu().unwrap();
-
-        let target_data = get_cpu_data(boot_cpu as _);
-        let _lock = target_data.ctrl_lock.lock();
+        let config = HvZoneConfig::read_guest(&this_zone().read(), config_addr as _)?;
+        info!("hv_zone_start: config: {:#x?}", config);
+        let zone = zone_create(&config)?;
+        let zone_r = zone.read();
+        let boot_cpu = zone_r.cpu_set.first_cpu().unwrap();
 
-        if !target_data.arch_cpu.psci_on {
-            send_event(boot_cpu, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
-        } else {
+        if !sched::wake(boot_cpu, zone_r.id, zone_r.entry_point) {
             error!("hv_zone_start: cpu {} already on", boot_cpu);
+            drop(zone_r);
+            zone_shutdown(zone);
             return hv_result_err!(EBUSY);
-        };
-        drop(_lock);
+        }
         HyperCallResult::Ok(0)
     }
 
@@ -161,24 +314,302 @@ impl<'a> HyperCall<'a> {
         }
         let zone = match find_zone(zone_id as _) {
             Some(zone) => zone,
-            _ => return hv_result_err!(EEXIST),
+            _ => return hv_result_err!(ENOENT),
         };
-        let zone_r = zone.read();
+        zone_shutdown(zone);
+        HyperCallResult::Ok(0)
+    }
+
+    // Fill `zone_info` with the status of at most `cnt` zones and return the total number of zones.
+    // E2BIG if the memory regions of a zone didn't fit, the infos are written anyway.
+    fn hv_zone_list(&self, zone_info_addr: u64, cnt: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "List zone operation over non-root zones: unsupported!"
+            );
+        }
+        let infos = zone_list_info();
+        let root_zone = this_zone();
+        let root_zone = root_zone.read();
+        for (i, info) in infos.iter().take(cnt as _).enumerate() {
+            root_zone
+                .gpm
+                .write_guest(zone_info_addr as usize + i * size_of::<HvZoneInfo>(), info)?;
+        }
+        if let Some(info) = infos
+            .iter()
+            .take(cnt as _)
+            .find(|info| info.num_memory_regions as usize > CONFIG_MAX_MEMORY_REGIONS)
+        {
+            return hv_result_err!(
+                E2BIG,
+                format!(
+                    "Zone {} has {} memory regions",
+                    info.zone_id, info.num_memory_regions
+                )
+            );
+        }
+        HyperCallResult::Ok(infos.len())
+    }
+
+    fn hv_zone_pause(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone pause, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Pause zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone.read().suspend();
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_zone_resume(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone resume, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Resume zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone.read().resume();
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_zone_reboot(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone reboot, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Reboot zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone.read().reboot();
+        HyperCallResult::Ok(0)
+    }
+
+    // Fill `info` with the hypervisor build information and return the ABI version.
+    fn hv_get_info(&self, info_addr: u64) -> HyperCallResult {
+        this_zone()
+            .read()
+            .gpm
+            .write_guest(info_addr as _, &HV_INFO)?;
+        HyperCallResult::Ok(HV_ABI_VERSION as _)
+    }
+
+    fn hv_zone_add_cpu(&mut self, zone_id: u64, cpu_id: u64) -> HyperCallResult {
+        info!("handle hvc zone add cpu, id={}, cpu={}", zone_id, cpu_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Add cpu zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone_add_cpu(&zone, cpu_id as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_zone_remove_cpu(&mut self, zone_id: u64, cpu_id: u64) -> HyperCallResult {
+        info!("handle hvc zone remove cpu, id={}, cpu={}", zone_id, cpu_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Remove cpu zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone_remove_cpu(&zone, cpu_id as _)?;
+        HyperCallResult::Ok(0)
+    }
 
-        // // return zone's cpus to root_zone
-        zone_r.cpu_set.iter().for_each(|cpu_id| {
-            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
-            get_cpu_data(cpu_id).zone = None;
-            get_cpu_data(cpu_id).cpu_on_entry = INVALID_ADDRESS;
-            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
-        });
+    fn hv_debug_console_putc(&self, c: u64) -> HyperCallResult {
+        debug_console::putc(this_zone_id(), c as u8);
+        HyperCallResult::Ok(0)
+    }
 
-        zone_r.arch_irqchip_reset();
+    // Output the `len` bytes at `addr`, return the number of bytes written.
+    fn hv_debug_console_puts(&self, addr: u64, len: u64) -> HyperCallResult {
+        let zone = this_zone();
+        let zone = zone.read();
+        if len as usize > debug_console::PUTS_MAX {
+            return hv_result_err!(
+                EINVAL,
+                format!("Output longer than {} bytes", debug_console::PUTS_MAX)
+            );
+        }
+        let mut buf = [0u8; 64];
+        let mut written = 0;
+        while written < len as usize {
+            let chunk = buf.len().min(len as usize - written);
+            zone.gpm
+                .copy_from_guest(addr as usize + written, &mut buf[..chunk])?;
+            debug_console::puts(zone.id, &buf[..chunk]);
+            written += chunk;
+        }
+        HyperCallResult::Ok(written)
+    }
 
-        drop(zone_r);
-        drop(zone);
-        remove_zone(zone_id as _);
+    // Return the next input character, or -1 if there is none.
+    fn hv_debug_console_getc(&self) -> HyperCallResult {
+        if !is_this_console_zone() {
+            return hv_result_err!(EPERM, "Console input belongs to another zone");
+        }
+        match debug_console::getc() {
+            Some(c) => HyperCallResult::Ok(c as _),
+            None => HyperCallResult::Ok(usize::MAX),
+        }
+    }
 
+    fn hv_get_cpu_stats(&self, cpu_id: u64, stats_addr: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Get stats over non-root zones: unsupported!");
+        }
+        if !STATS_ENABLED {
+            return hv_result_err!(ENODEV, "hvisor is built without STATS=on");
+        }
+        if cpu_id as usize >= MAX_CPU_NUM {
+            return hv_result_err!(EINVAL);
+        }
+        this_zone()
+            .read()
+            .gpm
+            .write_guest(stats_addr as _, &cpu_stats(cpu_id as _))?;
         HyperCallResult::Ok(0)
     }
+
+    fn hv_get_zone_stats(&self, zone_id: u64, stats_addr: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Get stats over non-root zones: unsupported!");
+        }
+        if !STATS_ENABLED {
+            return hv_result_err!(ENODEV, "hvisor is built without STATS=on");
+        }
+        let stats = match find_zone(zone_id as _) {
+            Some(zone) => zone.read().stats_info(),
+            _ => return hv_result_err!(ENOENT),
+        };
+        this_zone()
+            .read()
+            .gpm
+            .write_guest(stats_addr as _, &stats)?;
+        HyperCallResult::Ok(0)
+    }
+
+    // Open the channel whose NUL padded name is at `name_addr`, return its handle.
+    fn hv_channel_open(&self, name_addr: u64) -> HyperCallResult {
+        let zone = this_zone();
+        let zone = zone.read();
+        let name: [u8; CONFIG_CHANNEL_NAME_LEN] = unsafe { zone.gpm.read_guest(name_addr as _)? };
+        mailbox::open(zone.id, &name)
+    }
+
+    fn hv_channel_send(&self, handle: u64, msg_addr: u64) -> HyperCallResult {
+        let (zone_id, msg) = {
+            let zone = this_zone();
+            let zone = zone.read();
+            let msg: MailboxMsg = unsafe { zone.gpm.read_guest(msg_addr as _)? };
+            (zone.id, msg)
+        };
+        mailbox::send(zone_id, handle as _, &msg)?;
+        HyperCallResult::Ok(0)
+    }
+
+    // Copy the oldest message to `buf_addr`, return its size or 0 if there is none.
+    fn hv_channel_recv(&self, handle: u64, buf_addr: u64) -> HyperCallResult {
+        let zone = this_zone();
+        let zone = zone.read();
+        match mailbox::recv(zone.id, handle as _)? {
+            Some(msg) => {
+                zone.gpm.write_guest(buf_addr as _, &msg)?;
+                HyperCallResult::Ok(MAILBOX_MSG_SIZE)
+            }
+            None => HyperCallResult::Ok(0),
+        }
+    }
+
+    fn hv_watchdog_heartbeat(&self) -> HyperCallResult {
+        watchdog::heartbeat(this_zone_id())?;
+        HyperCallResult::Ok(0)
+    }
+
+    // Write the cyclic schedule statistics of zone `zone_id` to `info_addr`.
+    fn hv_get_sched_info(&self, zone_id: u64, info_addr: u64) -> HyperCallResult {
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Get schedule info over non-root zones: unsupported!");
+        }
+        let info = match find_zone(zone_id as _) {
+            Some(zone) => zone.read().sched_info(),
+            _ => return hv_result_err!(ENOENT),
+        };
+        this_zone().read().gpm.write_guest(info_addr as _, &info)?;
+        HyperCallResult::Ok(0)
+    }
+
+    // Write the checkpoint of zone `zone_id` to `buf_addr`, return its size.
+    fn hv_zone_checkpoint(&self, zone_id: u64, buf_addr: u64) -> HyperCallResult {
+        info!("handle hvc zone checkpoint, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Checkpoint zone operation over non-root zones: unsupported!"
+            );
+        }
+        if zone_id == 0 {
+            return hv_result_err!(EINVAL);
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        checkpoint::checkpoint(&zone, buf_addr as _)
+    }
+
+    // Create a zone from the checkpoint at `buf_addr`, return its id.
+    fn hv_zone_restore(&self, buf_addr: u64) -> HyperCallResult {
+        info!("handle hvc zone restore, buf_addr={:#x}", buf_addr);
+        if !is_this_root_zone() {
+            return hv_result_err!(
+                EPERM,
+                "Restore zone operation over non-root zones: unsupported!"
+            );
+        }
+        let zone = checkpoint::restore(buf_addr as _)?;
+        let id = zone.read().id;
+        HyperCallResult::Ok(id)
+    }
 }
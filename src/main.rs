@@ -28,6 +28,7 @@ extern crate lazy_static;
 #[macro_use]
 mod logging;
 mod arch;
+mod checkpoint;
 mod consts;
 mod device;
 mod event;
@@ -36,13 +37,16 @@ mod memory;
 mod panic;
 mod percpu;
 mod platform;
+mod sched;
+mod stats;
+mod watchdog;
 mod zone;
 mod config;
 
 use crate::arch::mm::setup_parange;
 use crate::consts::MAX_CPU_NUM;
 use arch::{cpu::cpu_start, entry::arch_entry};
-use config::root_zone_config;
+use config::boot_zone_configs;
 use zone::zone_create;
 use core::sync::atomic::{AtomicI32, AtomicU32, Ordering};
 use percpu::PerCpu;
@@ -77,7 +81,7 @@ fn wait_for_counter(counter: &AtomicU32, max_value: u32) {
     wait_for(|| counter.load(Ordering::Acquire) < max_value)
 }
 
-fn primary_init_early() {
+fn primary_init_early(host_dtb: usize) {
     extern "C" {
         fn __core_end();
     }
@@ -99,17 +103,25 @@ fn primary_init_early() {
     memory::frame::init();
     memory::frame::test();
     event::init(MAX_CPU_NUM);
+    config::init(host_dtb);
+    #[cfg(feature = "embed_images")]
+    platform::embedded::load_images();
 
     device::irqchip::primary_init_early();
     // crate::arch::mm::init_hv_page_table().unwrap();
 
-    zone_create(root_zone_config()).unwrap();
+    watchdog::init_early();
+    // The root zone, or every zone of a static partitioning.
+    for config in boot_zone_configs() {
+        zone_create(config).unwrap();
+    }
     INIT_EARLY_OK.store(1, Ordering::Release);
 }
 
 fn primary_init_late() {
     info!("Primary CPU init late...");
     device::irqchip::primary_init_late();
+    watchdog::init();
 
     INIT_LATE_OK.store(1, Ordering::Release);
 }
@@ -170,7 +182,7 @@ fn rust_main(cpuid: usize, host_dtb: usize) {
     setup_parange();
 
     if is_primary {
-        primary_init_early(); // create root zone here
+        primary_init_early(host_dtb); // create the boot zones here
     } else {
         wait_for_counter(&INIT_EARLY_OK, 1);
     }
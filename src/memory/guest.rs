@@ -0,0 +1,102 @@
+//! Access to guest memory from the hypervisor.
+//!
+//! Guest physical addresses handed to the hypervisor (e.g. hypercall
+//! arguments) are translated through the zone's stage 2 page table, so the
+//! guest can only make the hypervisor touch memory it owns itself.
+
+use core::mem::{size_of, MaybeUninit};
+use core::slice;
+
+use super::addr::{phys_to_virt, GuestPhysAddr, HostPhysAddr};
+use super::{MemFlags, MemorySet};
+use crate::arch::Stage2PageTable;
+use crate::error::HvResult;
+
+impl MemorySet<Stage2PageTable> {
+    /// Translate `gpa` and check that the page is normal memory with `flags`.
+    /// Returns the host physical address and the bytes left in its page.
+    fn translate_guest(
+        &self,
+        gpa: GuestPhysAddr,
+        flags: MemFlags,
+    ) -> HvResult<(HostPhysAddr, usize)> {
+        let (hpa, page_flags, page_size) = unsafe { self.page_table_query(gpa)? };
+        if !page_flags.contains(flags) || page_flags.contains(MemFlags::IO) {
+            return hv_result_err!(
+                EFAULT,
+                format!("guest address {:#x?} not accessible: {:?}", gpa, page_flags)
+            );
+        }
+        Ok((hpa, page_size as usize - page_size.page_offset(gpa)))
+    }
+
+    /// Translate a guest range that must be backed by contiguous host memory,
+    /// e.g. a region shared with the hypervisor for a long time.
+    pub fn translate_guest_range(
+        &self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MemFlags,
+    ) -> HvResult<HostPhysAddr> {
+        let end = gpa.checked_add(size).ok_or(hv_err!(EFAULT))?;
+        let (start_hpa, _) = self.translate_guest(gpa, flags)?;
+        let mut addr = gpa;
+        while addr < end {
+            let (hpa, len) = self.translate_guest(addr, flags)?;
+            if hpa != start_hpa + (addr - gpa) {
+                return hv_result_err!(
+                    EFAULT,
+                    format!("guest range {:#x?} is not contiguous", gpa..end)
+                );
+            }
+            addr += len;
+        }
+        Ok(start_hpa)
+    }
+
+    /// Copy `buf.len()` bytes from guest physical address `gpa` into `buf`.
+    pub fn copy_from_guest(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HvResult {
+        gpa.checked_add(buf.len()).ok_or(hv_err!(EFAULT))?;
+        let mut copied = 0;
+        while copied < buf.len() {
+            let (hpa, len) = self.translate_guest(gpa + copied, MemFlags::READ)?;
+            let len = len.min(buf.len() - copied);
+            let src = unsafe { slice::from_raw_parts(phys_to_virt(hpa) as *const u8, len) };
+            buf[copied..copied + len].copy_from_slice(src);
+            copied += len;
+        }
+        Ok(())
+    }
+
+    /// Copy `buf` to guest physical address `gpa`.
+    pub fn copy_to_guest(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HvResult {
+        gpa.checked_add(buf.len()).ok_or(hv_err!(EFAULT))?;
+        let mut copied = 0;
+        while copied < buf.len() {
+            let (hpa, len) = self.translate_guest(gpa + copied, MemFlags::WRITE)?;
+            let len = len.min(buf.len() - copied);
+            let dst = unsafe { slice::from_raw_parts_mut(phys_to_virt(hpa) as *mut u8, len) };
+            dst.copy_from_slice(&buf[copied..copied + len]);
+            copied += len;
+        }
+        Ok(())
+    }
+
+    /// Read a `T` from guest physical address `gpa`.
+    ///
+    /// # Safety
+    ///
+    /// Any bit pattern must be a valid `T`.
+    pub unsafe fn read_guest<T: Copy>(&self, gpa: GuestPhysAddr) -> HvResult<T> {
+        let mut val = MaybeUninit::<T>::uninit();
+        let buf = slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>());
+        self.copy_from_guest(gpa, buf)?;
+        Ok(val.assume_init())
+    }
+
+    /// Write `val` to guest physical address `gpa`.
+    pub fn write_guest<T: Copy>(&self, gpa: GuestPhysAddr, val: &T) -> HvResult {
+        let buf = unsafe { slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+        self.copy_to_guest(gpa, buf)
+    }
+}
@@ -3,6 +3,7 @@
 //! Memory management.
 
 use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
 use spin::Once;
 
@@ -49,6 +50,11 @@ impl<VA: From<usize> + Into<usize> + Copy> MemoryRegion<VA> {
         let p3 = p2 + other.size;
         !(p1 <= p2 || p0 >= p3)
     }
+
+    /// The part of this region between `start` and `end`.
+    fn slice(&self, start: usize, end: usize) -> Self {
+        Self::new(start.into(), end - start, self.flags, self.mapper.clone())
+    }
 }
 
 impl<PT: GenericPageTable> MemorySet<PT>
@@ -107,6 +113,7 @@ where
         if let Entry::Occupied(e) = self.regions.entry(start) {
             self.pt.unmap(e.get())?;
             e.remove();
+            self.pt.flush(None);
             Ok(())
         } else {
             hv_result_err!(
@@ -119,11 +126,90 @@ where
         }
     }
 
+    /// Remove the range `start..start + size` from this set, splitting the
+    /// regions it partially covers. Returns the removed parts.
+    pub fn unmap_partial(
+        &mut self,
+        start: PT::VA,
+        size: usize,
+    ) -> HvResult<Vec<MemoryRegion<PT::VA>>> {
+        assert!(is_aligned(start.into()));
+        assert!(is_aligned(size));
+        let range = MemoryRegion::new(start, size, MemFlags::empty(), Mapper::Offset(0));
+        let overlapped: Vec<MemoryRegion<PT::VA>> = self
+            .regions
+            .values()
+            .filter(|region| region.is_overlap_with(&range))
+            .cloned()
+            .collect();
+
+        let (start, end) = (start.into(), start.into() + size);
+        let mut kept = Vec::new();
+        let mut removed = Vec::new();
+        for region in &overlapped {
+            let region_start = region.start.into();
+            let region_end = region_start + region.size;
+            let (cut_start, cut_end) = (region_start.max(start), region_end.min(end));
+            if region_start < cut_start {
+                kept.push(region.slice(region_start, cut_start));
+            }
+            if cut_end < region_end {
+                kept.push(region.slice(cut_end, region_end));
+            }
+            removed.push(region.slice(cut_start, cut_end));
+        }
+
+        // The regions are only updated once the page table is, so that a
+        // failure leaves this set as it was.
+        let result = self.remap(&overlapped, &kept);
+        self.pt.flush(None);
+        result?;
+        for region in &overlapped {
+            self.regions.remove(&region.start);
+        }
+        for region in kept {
+            self.regions.insert(region.start, region);
+        }
+        Ok(removed)
+    }
+
+    /// Replace the mappings of `old` with those of `new` in the page table,
+    /// mapping `old` again if that fails.
+    fn remap(&mut self, old: &[MemoryRegion<PT::VA>], new: &[MemoryRegion<PT::VA>]) -> HvResult {
+        for (i, region) in old.iter().enumerate() {
+            // Unmap the whole region, the page table may map it with huge pages.
+            if let Err(e) = self.pt.unmap(region) {
+                old[..=i].iter().for_each(|region| {
+                    self.pt.map(region).ok();
+                });
+                return Err(e);
+            }
+        }
+        for (i, region) in new.iter().enumerate() {
+            if let Err(e) = self.pt.map(region) {
+                new[..i].iter().for_each(|region| {
+                    self.pt.unmap(region).ok();
+                });
+                old.iter().for_each(|region| {
+                    self.pt.map(region).ok();
+                });
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
     pub fn clear(&mut self) {
         for region in self.regions.values() {
             self.pt.unmap(region).unwrap();
         }
         self.regions.clear();
+        self.pt.flush(None);
+    }
+
+    /// Iterate over the memory regions of this set, ordered by start address.
+    pub fn regions(&self) -> impl Iterator<Item = &MemoryRegion<PT::VA>> {
+        self.regions.values()
     }
 
     pub unsafe fn activate(&self) {
@@ -1,4 +1,5 @@
 use core::ptr;
+use core::sync::atomic::AtomicU64;
 
 use crate::{error::HvResult, percpu::this_zone};
 
@@ -30,6 +31,8 @@ pub struct MMIOConfig {
     pub region: MMIORegion,
     pub handler: MMIOHandler,
     pub arg: usize,
+    /// Number of accesses, only counted with `STATS=on`.
+    pub hits: AtomicU64,
 }
 
 impl MMIORegion {
@@ -1,5 +1,6 @@
 pub mod addr;
 pub mod frame;
+pub mod guest;
 pub mod heap;
 pub mod mapper;
 pub mod mm;
@@ -32,6 +33,10 @@ bitflags! {
         const ROOTSHARED    = 1 << 7;
         const NO_HUGEPAGES  = 1 << 8;
         const USER          = 1 << 9;
+        /// Device memory, also outside IO regions.
+        const DEVICE        = 1 << 10;
+        /// Normal non-cacheable memory.
+        const NO_CACHE      = 1 << 11;
     }
 }
 
@@ -4,10 +4,12 @@ use spin::{Mutex, RwLock};
 use crate::arch::cpu::{this_cpu_id, ArchCpu};
 use crate::consts::{INVALID_ADDRESS, PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
 use crate::memory::addr::VirtAddr;
+use crate::event::{send_event, IPI_EVENT_SUSPEND};
+use crate::hypercall::SGI_IPI_ID;
 use crate::zone::Zone;
-use crate::ENTERED_CPUS;
+use crate::{wait_for, ENTERED_CPUS};
 use core::fmt::Debug;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 // global_asm!(include_str!("./arch/aarch64/page_table.S"),);
 
@@ -20,6 +22,10 @@ pub struct PerCpu {
     pub zone: Option<Arc<RwLock<Zone>>>,
     pub ctrl_lock: Mutex<()>,
     pub boot_cpu: bool,
+    /// Set by another cpu to park this cpu in EL2, see `suspend_cpu`.
+    pub suspend_cpu: AtomicBool,
+    /// Set by this cpu while it is parked in EL2.
+    pub cpu_suspended: AtomicBool,
     // percpu stack
 }
 
@@ -36,6 +42,8 @@ impl PerCpu {
                 zone: None,
                 ctrl_lock: Mutex::new(()),
                 boot_cpu: false,
+                suspend_cpu: AtomicBool::new(false),
+                cpu_suspended: AtomicBool::new(false),
             })
         };
         #[cfg(target_arch = "riscv64")]
@@ -46,7 +54,7 @@ impl PerCpu {
         unsafe { ret.as_mut().unwrap() }
     }
 
-    pub fn run_vm(&mut self) {
+    pub fn run_vm(&mut self) -> ! {
         if !self.boot_cpu {
             info!("CPU{}: Idling the CPU before starting VM...", self.id);
             self.arch_cpu.idle();
@@ -64,6 +72,42 @@ impl PerCpu {
             self.zone.clone().unwrap().read().gpm.activate();
         }
     }
+
+    /// Spin in EL2 as long as another cpu wants this cpu suspended. The guest
+    /// registers stay saved on the stack, so the guest continues where it was
+    /// interrupted once this returns.
+    pub fn wait_for_resume(&self) {
+        let mut lock = self.ctrl_lock.lock();
+        while self.suspend_cpu.load(Ordering::Acquire) {
+            self.cpu_suspended.store(true, Ordering::Release);
+            drop(lock);
+            wait_for(|| self.suspend_cpu.load(Ordering::Acquire));
+            lock = self.ctrl_lock.lock();
+        }
+        self.cpu_suspended.store(false, Ordering::Release);
+        drop(lock);
+    }
+}
+
+/// Park `cpu_id` in EL2 and wait until it has stopped running guest code.
+pub fn suspend_cpu(cpu_id: usize) {
+    let target_data = get_cpu_data(cpu_id);
+    let lock = target_data.ctrl_lock.lock();
+    target_data.suspend_cpu.store(true, Ordering::Release);
+    let target_suspended = target_data.cpu_suspended.load(Ordering::Acquire);
+    drop(lock);
+
+    if !target_suspended {
+        send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SUSPEND);
+        wait_for(|| !target_data.cpu_suspended.load(Ordering::Acquire));
+    }
+}
+
+/// Let a cpu parked by `suspend_cpu` return to its guest.
+pub fn resume_cpu(cpu_id: usize) {
+    get_cpu_data(cpu_id)
+        .suspend_cpu
+        .store(false, Ordering::Release);
 }
 
 pub fn get_cpu_data<'a>(cpu_id: usize) -> &'a mut PerCpu {
@@ -0,0 +1,586 @@
+//! Zone configs from device trees, and device trees of zones.
+//!
+//! The root zone gets the RAM of the `/memory` nodes except the hypervisor's
+//! own, the cpus of `/cpus`, and the registers and SPIs of the enabled
+//! devices. Devices behind a `simple-bus` with address translation are given
+//! as the windows of the bus. The GIC is emulated and left out, also of the
+//! windows covering it.
+//!
+//! An optional `/chosen/hvisor` node overrides the defaults:
+//!
+//! - `entry`, `kernel-addr`, `dtb-addr`: where the root kernel and its device
+//!   tree are loaded. Required unless the platform built in knows them.
+//! - `cpus`: mask of the cpus of the root zone.
+//! - `memory`: `<address size>` pairs replacing the RAM of `/memory`.
+//!
+//! A zone is described by a node compatible with `hvisor,zone`:
+//!
+//! - `zone-id`: id of the zone.
+//! - `cpus`: mask of the cpus of the zone.
+//! - `memory`, `devices`, `virtio`: `<address size>` pairs of its RAM, device
+//!   registers and virtio devices, mapped at the same addresses.
+//! - `irqs`: the GIC interrupt numbers of its devices.
+//! - `entry`, `dtb-addr`, and `kernel-addr` which defaults to `entry`.
+//! - `kernel-image`, `dtb-image`, `initrd-image`: `<address size>` of the
+//!   images in the memory of the zone starting it, copied to the load
+//!   addresses. `initrd-addr` is where the initrd is loaded.
+//! - `bootargs`: generate the device tree of the zone with these bootargs,
+//!   see `guest_dtb`. `virtio-irqs` gives the interrupt of each `virtio`
+//!   pair.
+//!
+//! Such children of `/chosen/hvisor` are the zones started at boot, their
+//! images must be in place already. Zone 0 is the root zone and comes alone,
+//! other zones partition the board statically without a root zone. The root
+//! zone also starts zones with a device tree holding such a node, see
+//! `dtb_zone_config`.
+
+use alloc::vec::Vec;
+use fdt::node::FdtNode;
+use fdt::Fdt;
+
+use super::{build_zone_config, fdt_builder::FdtBuilder, ROOT_ZONE_IMAGES};
+use crate::arch::cpu::mpidr_to_cpuid;
+use crate::arch::zone::HvArchZoneConfig;
+use crate::config::{
+    GuestDtbConfig, HvConfigMemoryRegion, HvZoneConfig, CONFIG_MAX_INTERRUPTS,
+    CONFIG_MAX_MEMORY_REGIONS, MEM_TYPE_IO, MEM_TYPE_RAM, MEM_TYPE_VIRTIO,
+};
+use crate::consts::{hv_end, hv_start, MAX_CPU_NUM};
+use crate::error::HvResult;
+use crate::memory::addr::{align_down, align_up, virt_to_phys};
+
+const GIC_COMPATIBLE: &[&str] = &["arm,gic-v3"];
+const ZONE_COMPATIBLE: &[&str] = &["hvisor,zone"];
+/// First cell of a GIC interrupt specifier of an SPI.
+const GIC_SPI: u32 = 0;
+const GIC_INTERRUPT_CELLS: usize = 3;
+/// First cell of a GIC interrupt specifier of a PPI.
+const GIC_PPI: u32 = 1;
+const IRQ_TYPE_EDGE_RISING: u32 = 1;
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+/// Phandle of the GIC in generated device trees.
+const GUEST_GIC_PHANDLE: u32 = 1;
+
+/// Read a number of `cells` big endian cells from the front of `bytes`.
+fn take_cells(bytes: &mut &[u8], cells: usize) -> Option<u64> {
+    if cells > 2 || bytes.len() < cells * 4 {
+        return None;
+    }
+    let (value, rest) = bytes.split_at(cells * 4);
+    *bytes = rest;
+    Some(value.chunks(4).fold(0, |acc, cell| {
+        (acc << 32) | u32::from_be_bytes(cell.try_into().unwrap()) as u64
+    }))
+}
+
+fn prop_u32(node: FdtNode, name: &str) -> Option<u32> {
+    node.property(name)
+        .and_then(|prop| take_cells(&mut &prop.value[..], 1))
+        .map(|value| value as u32)
+}
+
+/// The cells of the property `name` of `node`, empty if it's missing.
+fn prop_u32s(node: FdtNode, name: &str) -> Vec<u32> {
+    node.property(name)
+        .map(|prop| prop.value.chunks_exact(4))
+        .into_iter()
+        .flatten()
+        .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+        .collect()
+}
+
+fn prop_u64(node: FdtNode, name: &str) -> Option<u64> {
+    node.property(name)
+        .and_then(|prop| prop.as_usize())
+        .map(|value| value as u64)
+}
+
+/// The `<address size>` pairs of the property `name` of `node`, in the cells
+/// of the root node.
+fn prop_ranges(fdt: &Fdt, node: FdtNode, name: &str) -> Option<Vec<(usize, usize)>> {
+    let cells = fdt.root().cell_sizes();
+    let mut bytes = node.property(name)?.value;
+    let mut ret = Vec::new();
+    while let (Some(start), Some(size)) = (
+        take_cells(&mut bytes, cells.address_cells),
+        take_cells(&mut bytes, cells.size_cells),
+    ) {
+        ret.push((start as usize, size as usize));
+    }
+    Some(ret)
+}
+
+fn is_enabled(node: FdtNode) -> bool {
+    node.property("status")
+        .and_then(|prop| prop.as_str())
+        .map_or(true, |status| status == "okay" || status == "ok")
+}
+
+fn is_compatible(node: FdtNode, with: &[&str]) -> bool {
+    node.compatible().map_or(false, |compatible| {
+        compatible.all().any(|c| with.contains(&c))
+    })
+}
+
+/// Remove `hole` from the `(start, size)` ranges.
+fn punch_hole(ranges: Vec<(usize, usize)>, hole: (usize, usize)) -> Vec<(usize, usize)> {
+    let (hole_start, hole_end) = hole;
+    let mut ret = Vec::new();
+    for (start, size) in ranges {
+        let end = start + size;
+        if end <= hole_start || start >= hole_end {
+            ret.push((start, size));
+            continue;
+        }
+        if start < hole_start {
+            ret.push((start, hole_start - start));
+        }
+        if end > hole_end {
+            ret.push((hole_end, end - hole_end));
+        }
+    }
+    ret
+}
+
+/// Page align the `(start, size)` ranges and merge those that touch.
+fn merge_ranges(mut ranges: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    ranges.sort_unstable();
+    let mut ret: Vec<(usize, usize)> = Vec::new();
+    for (start, size) in ranges {
+        let (start, end) = (align_down(start), align_up(start + size));
+        match ret.last_mut() {
+            Some((last_start, last_size)) if start <= *last_start + *last_size => {
+                *last_size = (*last_size).max(end - *last_start);
+            }
+            _ => ret.push((start, end - start)),
+        }
+    }
+    ret
+}
+
+/// RAM of the `/memory` nodes, or of the `memory` override.
+fn ram_ranges(fdt: &Fdt, chosen: Option<FdtNode>) -> Vec<(usize, usize)> {
+    if let Some(ranges) = chosen.and_then(|node| prop_ranges(fdt, node, "memory")) {
+        return ranges;
+    }
+    fdt.all_nodes()
+        .filter(|node| node.name.split('@').next() == Some("memory") && is_enabled(*node))
+        .filter_map(|node| node.reg())
+        .flatten()
+        .filter_map(|region| Some((region.starting_address as usize, region.size?)))
+        .collect()
+}
+
+/// Registers of the enabled devices below `bus`.
+fn device_ranges(fdt: &Fdt, bus: FdtNode, ranges: &mut Vec<(usize, usize)>) {
+    for node in bus.children().filter(|node| is_enabled(*node)) {
+        let name = node.name.split('@').next().unwrap_or("");
+        if matches!(
+            name,
+            "memory" | "cpus" | "chosen" | "aliases" | "reserved-memory"
+        ) || is_compatible(node, GIC_COMPATIBLE)
+        {
+            continue;
+        }
+        if is_compatible(node, &["simple-bus"]) {
+            match node.property("ranges").map(|prop| prop.value) {
+                // Identity mapped, the children's registers are ours.
+                Some([]) | None => device_ranges(fdt, node, ranges),
+                Some(mut bytes) => {
+                    let child = node.cell_sizes();
+                    let parent = bus.cell_sizes();
+                    while let (Some(_), Some(start), Some(size)) = (
+                        take_cells(&mut bytes, child.address_cells),
+                        take_cells(&mut bytes, parent.address_cells),
+                        take_cells(&mut bytes, child.size_cells),
+                    ) {
+                        ranges.push((start as usize, size as usize));
+                    }
+                }
+            }
+            continue;
+        }
+        if let Some(reg) = node.reg() {
+            ranges.extend(
+                reg.filter_map(|region| Some((region.starting_address as usize, region.size?))),
+            );
+        }
+    }
+}
+
+/// SPIs of the enabled devices from `node` down whose interrupt parent is
+/// the GIC, `parent` being the interrupt parent inherited from above.
+fn device_irqs(fdt: &Fdt, node: FdtNode, parent: Option<u32>, gic: u32, irqs: &mut Vec<u32>) {
+    if !is_enabled(node) {
+        return;
+    }
+    let parent = prop_u32(node, "interrupt-parent").or(parent);
+    let mut push_spi = |bytes: &mut &[u8]| {
+        if let (Some(kind), Some(num), Some(_)) = (
+            take_cells(bytes, 1),
+            take_cells(bytes, 1),
+            take_cells(bytes, 1),
+        ) {
+            if kind as u32 == GIC_SPI && num < 988 {
+                irqs.push(num as u32 + 32);
+            }
+        }
+    };
+    if let Some(prop) = node.property("interrupts") {
+        if parent == Some(gic) {
+            let mut bytes = prop.value;
+            while bytes.len() >= GIC_INTERRUPT_CELLS * 4 {
+                push_spi(&mut bytes);
+            }
+        }
+    }
+    if let Some(prop) = node.property("interrupts-extended") {
+        let mut bytes = prop.value;
+        while let Some(phandle) = take_cells(&mut bytes, 1) {
+            if phandle as u32 == gic {
+                push_spi(&mut bytes);
+                continue;
+            }
+            let cells = fdt
+                .find_phandle(phandle as u32)
+                .and_then(|ctrl| ctrl.interrupt_cells())
+                .unwrap_or(0);
+            bytes = bytes.get(cells * 4..).unwrap_or(&[]);
+        }
+    }
+    for child in node.children() {
+        device_irqs(fdt, child, parent, gic, irqs);
+    }
+}
+
+fn parse(host_dtb: usize) -> HvResult<Fdt<'static>> {
+    unsafe { Fdt::from_ptr(host_dtb as *const u8) }
+        .map_err(|e| hv_err!(EINVAL, format!("bad host dtb: {}", e)))
+}
+
+/// The GICv3 of the host and the zone config describing it.
+fn gic_config<'b, 'a>(fdt: &'b Fdt<'a>) -> HvResult<(FdtNode<'b, 'a>, HvArchZoneConfig)> {
+    let gic = fdt
+        .find_compatible(GIC_COMPATIBLE)
+        .ok_or(hv_err!(ENODEV, "no GICv3 in dtb"))?;
+    let mut gic_reg = gic
+        .reg()
+        .ok_or(hv_err!(ENODEV, "GICv3 without registers"))?;
+    let (gicd, gicr) = match (gic_reg.next(), gic_reg.next()) {
+        (Some(gicd), Some(gicr)) => (gicd, gicr),
+        _ => return hv_result_err!(ENODEV, "GICv3 without redistributors"),
+    };
+    let (gicd_size, gicr_size) = match (gicd.size, gicr.size) {
+        (Some(gicd_size), Some(gicr_size)) => (gicd_size, gicr_size),
+        _ => return hv_result_err!(ENODEV, "GICv3 registers without size"),
+    };
+    let arch = HvArchZoneConfig {
+        gicd_base: gicd.starting_address as _,
+        gicr_base: gicr.starting_address as _,
+        gicd_size,
+        gicr_size,
+    };
+    Ok((gic, arch))
+}
+
+/// MMIO the hypervisor keeps to itself as `(start, end)`: the distributor and
+/// redistributors of the GIC, which zones only reach through the vGIC.
+fn hv_mmio_ranges(arch: &HvArchZoneConfig) -> [(usize, usize); 2] {
+    [
+        (arch.gicd_base, arch.gicd_base + arch.gicd_size),
+        (arch.gicr_base, arch.gicr_base + arch.gicr_size),
+    ]
+}
+
+/// Identity mapped memory regions of the `ram`, `io` and `virtio` ranges.
+fn memory_regions(
+    ram: &[(usize, usize)],
+    io: &[(usize, usize)],
+    virtio: &[(usize, usize)],
+) -> HvResult<Vec<HvConfigMemoryRegion>> {
+    let regions: Vec<_> = ram
+        .iter()
+        .map(|range| (MEM_TYPE_RAM, range))
+        .chain(io.iter().map(|range| (MEM_TYPE_IO, range)))
+        .chain(virtio.iter().map(|range| (MEM_TYPE_VIRTIO, range)))
+        .map(|(mem_type, &(start, size))| HvConfigMemoryRegion {
+            mem_type,
+            flags: 0,
+            physical_start: start as _,
+            virtual_start: start as _,
+            size: size as _,
+        })
+        .collect();
+    if regions.len() > CONFIG_MAX_MEMORY_REGIONS {
+        return hv_result_err!(E2BIG, format!("{} memory regions", regions.len()));
+    }
+    Ok(regions)
+}
+
+pub fn root_zone_config(host_dtb: usize) -> HvResult<HvZoneConfig> {
+    let fdt = parse(host_dtb)?;
+    let root = fdt.find_node("/").ok_or(hv_err!(EINVAL, "no root node"))?;
+    let chosen = fdt.find_node("/chosen/hvisor");
+    let chosen_u64 = |name: &str| chosen.and_then(|node| prop_u64(node, name));
+
+    let (gic, arch) = gic_config(&fdt)?;
+
+    let cpus = match chosen_u64("cpus") {
+        Some(cpus) => cpus,
+        None => fdt
+            .find_node("/cpus")
+            .ok_or(hv_err!(ENODEV, "no cpus in host dtb"))?
+            .children()
+            .filter(|node| node.name.split('@').next() == Some("cpu") && is_enabled(*node))
+            .filter_map(|node| node.reg()?.next())
+            .map(|reg| mpidr_to_cpuid(reg.starting_address as _) as usize)
+            .filter(|&cpu_id| cpu_id < MAX_CPU_NUM)
+            .fold(0, |cpus, cpu_id| cpus | 1 << cpu_id),
+    };
+
+    let (entry, kernel_addr, dtb_addr) = match (
+        chosen_u64("entry"),
+        chosen_u64("kernel-addr"),
+        chosen_u64("dtb-addr"),
+        ROOT_ZONE_IMAGES,
+    ) {
+        (Some(entry), kernel_addr, Some(dtb_addr), _) => {
+            (entry, kernel_addr.unwrap_or(entry), dtb_addr)
+        }
+        (None, None, None, Some(images)) => images,
+        _ => return hv_result_err!(ENOENT, "no root kernel in /chosen/hvisor"),
+    };
+
+    let hv_range = (virt_to_phys(hv_start()), virt_to_phys(hv_end()));
+    let ram = merge_ranges(punch_hole(ram_ranges(&fdt, chosen), hv_range));
+    let mut io = Vec::new();
+    device_ranges(&fdt, root, &mut io);
+    let io = hv_mmio_ranges(&arch)
+        .into_iter()
+        .fold(merge_ranges(io), punch_hole);
+    let regions = memory_regions(&ram, &io, &[])?;
+
+    let mut irqs = Vec::new();
+    if let Some(gic_phandle) = prop_u32(gic, "phandle") {
+        let parent = prop_u32(root, "interrupt-parent");
+        device_irqs(&fdt, root, parent, gic_phandle, &mut irqs);
+    }
+    irqs.sort_unstable();
+    irqs.dedup();
+    if irqs.len() > CONFIG_MAX_INTERRUPTS {
+        return hv_result_err!(E2BIG, format!("{} interrupts in host dtb", irqs.len()));
+    }
+
+    info!(
+        "root zone from host dtb: cpus {:#b}, {} ram and {} io regions, {} irqs",
+        cpus,
+        ram.len(),
+        io.len(),
+        irqs.len()
+    );
+    Ok(build_zone_config(
+        0,
+        cpus,
+        &regions,
+        &irqs,
+        entry,
+        kernel_addr,
+        dtb_addr,
+        arch,
+    ))
+}
+
+/// Config of the `hvisor,zone` node `node` of `fdt`, a zone on the GIC
+/// `arch`.
+fn zone_config(fdt: &Fdt, node: FdtNode, arch: HvArchZoneConfig) -> HvResult<HvZoneConfig> {
+    let (zone_id, cpus, entry, dtb_addr) = match (
+        prop_u32(node, "zone-id"),
+        prop_u64(node, "cpus"),
+        prop_u64(node, "entry"),
+        prop_u64(node, "dtb-addr"),
+    ) {
+        (Some(zone_id), Some(cpus), Some(entry), Some(dtb_addr)) => {
+            (zone_id, cpus, entry, dtb_addr)
+        }
+        _ => {
+            return hv_result_err!(
+                EINVAL,
+                format!("{}: needs zone-id, cpus, entry and dtb-addr", node.name)
+            )
+        }
+    };
+    let kernel_addr = prop_u64(node, "kernel-addr").unwrap_or(entry);
+    let ranges = |name| prop_ranges(fdt, node, name).unwrap_or_default();
+    let regions = memory_regions(&ranges("memory"), &ranges("devices"), &ranges("virtio"))?;
+    let irqs = prop_u32s(node, "irqs");
+    if irqs.len() > CONFIG_MAX_INTERRUPTS {
+        return hv_result_err!(E2BIG, format!("{}: {} irqs", node.name, irqs.len()));
+    }
+    let mut config = build_zone_config(
+        zone_id,
+        cpus,
+        &regions,
+        &irqs,
+        entry,
+        kernel_addr,
+        dtb_addr,
+        arch,
+    );
+
+    let image = |name| {
+        ranges(name)
+            .first()
+            .map(|&(buf, size)| (buf as u64, size as u64))
+    };
+    if let Some((buf, size)) = image("kernel-image") {
+        (config.kernel_image, config.kernel_size) = (buf, size);
+    }
+    if let Some((buf, size)) = image("dtb-image") {
+        (config.dtb_image, config.dtb_size) = (buf, size);
+    }
+    if let Some((buf, size)) = image("initrd-image") {
+        config.initrd_load_paddr = prop_u64(node, "initrd-addr").ok_or(hv_err!(
+            EINVAL,
+            format!("{}: initrd-image without initrd-addr", node.name)
+        ))?;
+        (config.initrd_image, config.initrd_size) = (buf, size);
+    }
+    if let Some(bootargs) = node.property("bootargs").and_then(|prop| prop.as_str()) {
+        config.guest_dtb = Some(GuestDtbConfig {
+            virtio_irqs: prop_u32s(node, "virtio-irqs"),
+            bootargs: bootargs.into(),
+        });
+    }
+    info!(
+        "zone {} from dtb: cpus {:#b}, {} memory regions, {} irqs",
+        zone_id,
+        cpus,
+        regions.len(),
+        irqs.len()
+    );
+    Ok(config)
+}
+
+/// Configs of the `hvisor,zone` nodes below `/chosen/hvisor`, the zones
+/// started at boot. Empty if there are none.
+pub fn static_zone_configs(host_dtb: usize) -> HvResult<Vec<HvZoneConfig>> {
+    let fdt = parse(host_dtb)?;
+    let chosen = match fdt.find_node("/chosen/hvisor") {
+        Some(chosen) => chosen,
+        None => return Ok(Vec::new()),
+    };
+    let nodes: Vec<_> = chosen
+        .children()
+        .filter(|node| is_enabled(*node) && is_compatible(*node, ZONE_COMPATIBLE))
+        .collect();
+    if nodes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let (_, arch) = gic_config(&fdt)?;
+    let configs = nodes
+        .into_iter()
+        .map(|node| zone_config(&fdt, node, arch))
+        .collect::<HvResult<Vec<_>>>()?;
+    if configs.len() > 1 && configs.iter().any(|config| config.zone_id == 0) {
+        return hv_result_err!(EINVAL, "the root zone 0 comes alone");
+    }
+    Ok(configs)
+}
+
+/// Config of the first `hvisor,zone` node of the device tree in `dtb`.
+pub fn dtb_zone_config(dtb: &[u8]) -> HvResult<HvZoneConfig> {
+    let fdt = Fdt::new(dtb).map_err(|e| hv_err!(EINVAL, format!("bad zone dtb: {}", e)))?;
+    let node = fdt
+        .find_compatible(ZONE_COMPATIBLE)
+        .ok_or(hv_err!(ENOENT, "no hvisor,zone node in dtb"))?;
+    let (_, arch) = gic_config(&fdt)?;
+    zone_config(&fdt, node, arch)
+}
+
+/// Device tree of a zone generated from `config`: its RAM, cpus, the GICv3,
+/// the timer, a virtio-mmio device for each virtio region and the bootargs.
+pub fn guest_dtb(config: &HvZoneConfig, guest_dtb: &GuestDtbConfig) -> Vec<u8> {
+    let cpus = config.cpus();
+    let arch = &config.arch;
+    let regions = |mem_type| {
+        config
+            .memory_regions()
+            .iter()
+            .filter(move |region| region.mem_type == mem_type)
+    };
+
+    let mut fdt = FdtBuilder::new();
+    fdt.begin_node("");
+    fdt.prop_u32("#address-cells", 2);
+    fdt.prop_u32("#size-cells", 2);
+    fdt.prop_u32("interrupt-parent", GUEST_GIC_PHANDLE);
+    fdt.prop_str("model", &format!("hvisor zone {}", config.zone_id));
+    fdt.prop_str("compatible", "linux,dummy-virt");
+
+    fdt.begin_node("cpus");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 0);
+    for cpu_id in cpus.iter() {
+        fdt.begin_node(&format!("cpu@{:x}", cpu_id));
+        fdt.prop_str("device_type", "cpu");
+        fdt.prop_str("compatible", "arm,armv8");
+        fdt.prop_str("enable-method", "psci");
+        fdt.prop_u32("reg", *cpu_id as _);
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("psci");
+    fdt.prop_str("compatible", "arm,psci-0.2");
+    fdt.prop_str("method", "smc");
+    fdt.end_node();
+
+    for region in regions(MEM_TYPE_RAM) {
+        fdt.begin_node(&format!("memory@{:x}", region.virtual_start));
+        fdt.prop_str("device_type", "memory");
+        fdt.prop_reg("reg", &[(region.virtual_start, region.size)]);
+        fdt.end_node();
+    }
+
+    fdt.begin_node(&format!("interrupt-controller@{:x}", arch.gicd_base));
+    fdt.prop_str("compatible", GIC_COMPATIBLE[0]);
+    fdt.prop_u32("#interrupt-cells", GIC_INTERRUPT_CELLS as _);
+    fdt.prop_empty("interrupt-controller");
+    fdt.prop_reg(
+        "reg",
+        &[
+            (arch.gicd_base as _, arch.gicd_size as _),
+            (arch.gicr_base as _, arch.gicr_size as _),
+        ],
+    );
+    fdt.prop_u32("phandle", GUEST_GIC_PHANDLE);
+    fdt.end_node();
+
+    // Secure, non-secure, virtual and hypervisor physical timers.
+    let ppi_flags = (((1 << cpus.len().min(8)) - 1) << 8) | IRQ_TYPE_LEVEL_HIGH;
+    let timer_irqs: Vec<u32> = [13, 14, 11, 10]
+        .into_iter()
+        .flat_map(|ppi| [GIC_PPI, ppi, ppi_flags])
+        .collect();
+    fdt.begin_node("timer");
+    fdt.prop_strs("compatible", &["arm,armv8-timer", "arm,armv7-timer"]);
+    fdt.prop_cells("interrupts", &timer_irqs);
+    fdt.prop_empty("always-on");
+    fdt.end_node();
+
+    for (region, irq) in regions(MEM_TYPE_VIRTIO).zip(guest_dtb.virtio_irqs.iter()) {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", region.virtual_start));
+        fdt.prop_str("compatible", "virtio,mmio");
+        fdt.prop_reg("reg", &[(region.virtual_start, region.size)]);
+        fdt.prop_cells("interrupts", &[GIC_SPI, irq - 32, IRQ_TYPE_EDGE_RISING]);
+        fdt.prop_empty("dma-coherent");
+        fdt.end_node();
+    }
+
+    fdt.begin_node("chosen");
+    fdt.prop_str("bootargs", &guest_dtb.bootargs);
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
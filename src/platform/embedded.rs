@@ -0,0 +1,58 @@
+//! Zones built into hvisor with the `embed_images` feature, generated by
+//! `build.rs` from the embed manifest.
+
+use core::slice;
+
+use super::{StaticZone, ROOT_ARCH_ZONE_CONFIG};
+use crate::arch::cache::{dcache_clean_invalidate_range, icache_invalidate_all};
+use crate::config::{HvConfigMemoryRegion, MEM_TYPE_RAM};
+use crate::consts::{hv_end, hv_start};
+use crate::memory::addr::{phys_to_virt, virt_to_phys};
+
+/// A kernel or device tree of the zone `zone_id`, copied to `load_paddr`.
+pub struct EmbeddedImage {
+    pub zone_id: u32,
+    pub load_paddr: usize,
+    pub data: &'static [u8],
+}
+
+include!(concat!(env!("OUT_DIR"), "/embedded_zones.rs"));
+
+/// Copy the embedded images to their load addresses in the RAM of their
+/// zones, before the zones are created.
+pub fn load_images() {
+    for image in EMBEDDED_IMAGES {
+        let (paddr, size) = (image.load_paddr, image.data.len());
+        let zone = EMBEDDED_ZONES
+            .iter()
+            .find(|zone| zone.zone_id == image.zone_id)
+            .unwrap();
+        if !zone.memory_regions.iter().any(|region| {
+            region.mem_type == MEM_TYPE_RAM
+                && region.physical_start as usize <= paddr
+                && paddr + size <= (region.physical_start + region.size) as usize
+        }) {
+            panic!(
+                "embedded image {:#x?} out of zone {} RAM",
+                paddr..paddr + size,
+                zone.zone_id
+            );
+        }
+        if paddr < virt_to_phys(hv_end()) && paddr + size > virt_to_phys(hv_start()) {
+            panic!(
+                "embedded image {:#x?} overlaps with hypervisor",
+                paddr..paddr + size
+            );
+        }
+        let vaddr = phys_to_virt(paddr);
+        let dst = unsafe { slice::from_raw_parts_mut(vaddr as *mut u8, size) };
+        dst.copy_from_slice(image.data);
+        dcache_clean_invalidate_range(vaddr, size);
+        info!(
+            "zone {}: embedded image loaded at {:#x?}",
+            zone.zone_id,
+            paddr..paddr + size
+        );
+    }
+    icache_invalidate_all();
+}
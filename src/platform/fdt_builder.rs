@@ -0,0 +1,148 @@
+//! Write side of the flattened device tree format, the vendored `fdt` crate
+//! only reads device trees.
+//!
+//! Nodes are written depth first: `begin_node`, the properties of the node,
+//! its children, then `end_node`. `finish` returns the device tree blob.
+
+use alloc::vec::Vec;
+
+use crate::config::FDT_MAGIC;
+
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+/// The memory reservation block, only its terminating entry.
+const FDT_RSVMAP_SIZE: usize = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+#[derive(Default)]
+pub struct FdtBuilder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+impl FdtBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    /// Append `bytes` to the structure block, padded to 4 bytes.
+    fn push_padded(&mut self, bytes: &[u8]) {
+        self.structure.extend_from_slice(bytes);
+        self.structure.resize((self.structure.len() + 3) & !3, 0);
+    }
+
+    /// Offset of `name` in the strings block, added if it's new.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&b| b == 0) {
+            if s == name.as_bytes() && offset < self.strings.len() {
+                return offset as _;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as _
+    }
+
+    /// Begin the node `name`, the first one being the root node "".
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.push(0);
+        self.push_padded(&bytes);
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "fdt: end_node without begin_node");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn prop(&mut self, name: &str, value: &[u8]) {
+        let nameoff = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as _);
+        self.push_u32(nameoff);
+        self.push_padded(value);
+    }
+
+    pub fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    pub fn prop_u32(&mut self, name: &str, value: u32) {
+        self.prop_cells(name, &[value]);
+    }
+
+    pub fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.prop(name, &value);
+    }
+
+    /// `<address size>` pairs of two cells each.
+    pub fn prop_reg(&mut self, name: &str, ranges: &[(u64, u64)]) {
+        let value: Vec<u8> = ranges
+            .iter()
+            .flat_map(|&(start, size)| [start.to_be_bytes(), size.to_be_bytes()])
+            .flatten()
+            .collect();
+        self.prop(name, &value);
+    }
+
+    pub fn prop_str(&mut self, name: &str, value: &str) {
+        self.prop_strs(name, &[value]);
+    }
+
+    /// A string list, e.g. `compatible`.
+    pub fn prop_strs(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for s in values {
+            value.extend_from_slice(s.as_bytes());
+            value.push(0);
+        }
+        self.prop(name, &value);
+    }
+
+    /// The device tree blob, all nodes must be ended.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert!(self.depth == 0, "fdt: node not ended");
+        self.push_u32(FDT_END);
+        let off_dt_struct = FDT_HEADER_SIZE + FDT_RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total_size as _,
+            off_dt_struct as _,
+            off_dt_strings as _,
+            FDT_HEADER_SIZE as _,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0,
+            self.strings.len() as _,
+            self.structure.len() as _,
+        ];
+
+        let mut dtb = Vec::with_capacity(total_size);
+        for field in header {
+            dtb.extend_from_slice(&field.to_be_bytes());
+        }
+        dtb.resize(off_dt_struct, 0);
+        dtb.extend_from_slice(&self.structure);
+        dtb.extend_from_slice(&self.strings);
+        dtb
+    }
+}
@@ -1,5 +1,7 @@
 use crate::{arch::zone::HvArchZoneConfig, config::*};
 
+use super::StaticZone;
+
 pub const ROOT_ZONE_DTB_ADDR: u64 = 0xa0000000;
 pub const ROOT_ZONE_KERNEL_ADDR: u64 = 0xa0400000;
 pub const ROOT_ZONE_ENTRY: u64 = 0xa0400000;
@@ -8,24 +10,28 @@ pub const ROOT_ZONE_CPUS: u64 = (1 << 0) | (1 << 1);
 pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 3] = [
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_RAM,
+        flags: 0,
         physical_start: 0x50000000,
         virtual_start: 0x50000000,
         size: 0x80000000,
     }, // ram
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_IO,
+        flags: 0,
         physical_start: 0x30000000,
         virtual_start: 0x30000000,
         size: 0x400000,
     }, // bus@30000000
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_IO,
+        flags: 0,
         physical_start: 0x30800000,
         virtual_start: 0x30800000,
         size: 0x400000,
     }, // bus@30800000
        // HvConfigMemoryRegion {
        //     mem_type: MEM_TYPE_IO,
+       //     flags: 0,
        //     physical_start: 0x30890000,
        //     virtual_start: 0x30890000,
        //     size: 0x1000,
@@ -42,3 +48,7 @@ pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     gicr_base: 0x38880000,
     gicr_size: 0xc0000,
 };
+
+/// Zones to partition the board into at boot, without a root zone. Empty
+/// boots the root zone above.
+pub const STATIC_ZONES: &[StaticZone] = &[];
@@ -1,10 +1,26 @@
+use alloc::vec::Vec;
+
 use crate::{
-    config::{
-        HvConfigMemoryRegion, HvZoneConfig, CONFIG_MAX_INTERRUPTS, CONFIG_MAX_MEMORY_REGIONS,
-    },
-    consts::INVALID_ADDRESS,
+    arch::zone::HvArchZoneConfig,
+    config::{HvConfigMemoryRegion, HvZoneConfig},
+    error::HvResult,
 };
 
+#[cfg(target_arch = "aarch64")]
+mod dtb;
+
+#[cfg(target_arch = "aarch64")]
+mod fdt_builder;
+
+#[cfg(feature = "embed_images")]
+pub mod embedded;
+
+#[cfg(all(
+    feature = "embed_images",
+    not(any(feature = "platform_qemu", feature = "platform_imx8mp"))
+))]
+compile_error!("embed_images needs a platform for the GIC of the embedded zones");
+
 #[cfg(all(feature = "platform_qemu", target_arch = "riscv64"))]
 pub mod qemu_riscv64;
 
@@ -23,33 +39,152 @@ pub mod imx8mp_aarch64;
 #[cfg(all(feature = "platform_imx8mp", target_arch = "aarch64"))]
 use imx8mp_aarch64::*;
 
-pub fn platform_root_zone_config() -> HvZoneConfig {
-    // fill zero for memory regions and interrupts
+/// Entry, kernel and device tree addresses of the root zone, if the platform
+/// built in knows them.
+#[cfg(any(feature = "platform_qemu", feature = "platform_imx8mp"))]
+const ROOT_ZONE_IMAGES: Option<(u64, u64, u64)> =
+    Some((ROOT_ZONE_ENTRY, ROOT_ZONE_KERNEL_ADDR, ROOT_ZONE_DTB_ADDR));
+#[cfg(not(any(feature = "platform_qemu", feature = "platform_imx8mp")))]
+const ROOT_ZONE_IMAGES: Option<(u64, u64, u64)> = None;
 
-    let mut memory_regions = [HvConfigMemoryRegion {
-        mem_type: 0,
-        physical_start: 0,
-        virtual_start: 0,
-        size: 0,
-    }; CONFIG_MAX_MEMORY_REGIONS];
+#[cfg(not(any(feature = "platform_qemu", feature = "platform_imx8mp")))]
+const STATIC_ZONES: &[StaticZone] = &[];
 
-    memory_regions[..ROOT_ZONE_MEMORY_REGIONS.len()].copy_from_slice(&ROOT_ZONE_MEMORY_REGIONS);
+/// A zone started at boot from a config built into hvisor. Its images are
+/// in place already, or embedded.
+pub struct StaticZone {
+    /// Non-zero when the board is partitioned statically. Zone 0 is the root
+    /// zone, which is started alone.
+    pub zone_id: u32,
+    pub cpus: u64,
+    pub memory_regions: &'static [HvConfigMemoryRegion],
+    pub irqs: &'static [u32],
+    pub entry: u64,
+    pub kernel_addr: u64,
+    pub dtb_addr: u64,
+    pub arch: HvArchZoneConfig,
+}
 
-    let mut interrupts = [0; CONFIG_MAX_INTERRUPTS];
-    interrupts[..ROOT_ZONE_IRQS.len()].copy_from_slice(&ROOT_ZONE_IRQS);
+impl StaticZone {
+    fn config(&self) -> HvZoneConfig {
+        build_zone_config(
+            self.zone_id,
+            self.cpus,
+            self.memory_regions,
+            self.irqs,
+            self.entry,
+            self.kernel_addr,
+            self.dtb_addr,
+            self.arch,
+        )
+    }
+}
 
-    HvZoneConfig::new(
+/// Configs of the zones to create at boot. Zones embedded in hvisor come
+/// first, then the `hvisor,zone` nodes of the host device tree at `host_dtb`
+/// and the `STATIC_ZONES` of the platform. Otherwise only the root zone is
+/// created, as the host device tree describes the board.
+#[cfg_attr(not(target_arch = "aarch64"), allow(unused_variables))]
+pub fn platform_boot_zone_configs(host_dtb: usize) -> Vec<HvZoneConfig> {
+    #[cfg(feature = "embed_images")]
+    if !embedded::EMBEDDED_ZONES.is_empty() {
+        info!("{} zones embedded", embedded::EMBEDDED_ZONES.len());
+        return embedded::EMBEDDED_ZONES
+            .iter()
+            .map(StaticZone::config)
+            .collect();
+    }
+    #[cfg(target_arch = "aarch64")]
+    match dtb::static_zone_configs(host_dtb) {
+        Ok(configs) if !configs.is_empty() => {
+            info!("{} zones from host dtb", configs.len());
+            return configs;
+        }
+        Ok(_) => {}
+        Err(e) => warn!("zones from host dtb failed: {:?}", e),
+    }
+    if !STATIC_ZONES.is_empty() {
+        info!("{} static zones from platform", STATIC_ZONES.len());
+        return STATIC_ZONES.iter().map(StaticZone::config).collect();
+    }
+    vec![platform_root_zone_config(host_dtb)]
+}
+
+/// Config of the `hvisor,zone` node of the device tree in `dtb`.
+#[cfg(target_arch = "aarch64")]
+pub fn dtb_zone_config(dtb: &[u8]) -> HvResult<HvZoneConfig> {
+    dtb::dtb_zone_config(dtb)
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+pub fn dtb_zone_config(_dtb: &[u8]) -> HvResult<HvZoneConfig> {
+    hv_result_err!(ENOSYS, "Zone configs from dtb: unsupported!")
+}
+
+/// Device tree of the zone of `config`, which must ask for one.
+#[cfg(target_arch = "aarch64")]
+pub fn guest_dtb(config: &HvZoneConfig) -> HvResult<Vec<u8>> {
+    match &config.guest_dtb {
+        Some(guest_dtb) => Ok(dtb::guest_dtb(config, guest_dtb)),
+        None => hv_result_err!(EINVAL, "Zone config without guest dtb"),
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+pub fn guest_dtb(_config: &HvZoneConfig) -> HvResult<Vec<u8>> {
+    hv_result_err!(ENOSYS, "Generating guest dtb: unsupported!")
+}
+
+/// Build the root zone config from the host device tree at `host_dtb`, or
+/// from the constants of the platform if the device tree can't be used.
+#[cfg_attr(not(target_arch = "aarch64"), allow(unused_variables))]
+fn platform_root_zone_config(host_dtb: usize) -> HvZoneConfig {
+    #[cfg(target_arch = "aarch64")]
+    match dtb::root_zone_config(host_dtb) {
+        Ok(config) => return config,
+        Err(e) => warn!("root zone config from host dtb failed: {:?}", e),
+    }
+    static_root_zone_config()
+}
+
+#[cfg(any(feature = "platform_qemu", feature = "platform_imx8mp"))]
+fn static_root_zone_config() -> HvZoneConfig {
+    build_zone_config(
         0,
         ROOT_ZONE_CPUS,
-        ROOT_ZONE_MEMORY_REGIONS.len() as u32,
-        memory_regions,
-        ROOT_ZONE_IRQS.len() as u32,
-        interrupts,
+        &ROOT_ZONE_MEMORY_REGIONS,
+        &ROOT_ZONE_IRQS,
         ROOT_ZONE_ENTRY,
         ROOT_ZONE_KERNEL_ADDR,
-        INVALID_ADDRESS as _,
         ROOT_ZONE_DTB_ADDR,
-        INVALID_ADDRESS as _,
         ROOT_ARCH_ZONE_CONFIG,
     )
 }
+
+#[cfg(not(any(feature = "platform_qemu", feature = "platform_imx8mp")))]
+fn static_root_zone_config() -> HvZoneConfig {
+    panic!("no root zone config: the host dtb is unusable and no platform is built in");
+}
+
+/// Build the config of a zone whose images are in place already.
+pub fn build_zone_config(
+    zone_id: u32,
+    cpus: u64,
+    regions: &[HvConfigMemoryRegion],
+    irqs: &[u32],
+    entry: u64,
+    kernel_addr: u64,
+    dtb_addr: u64,
+    arch: HvArchZoneConfig,
+) -> HvZoneConfig {
+    HvZoneConfig::new(
+        zone_id,
+        cpus,
+        regions.to_vec(),
+        irqs.to_vec(),
+        entry,
+        kernel_addr,
+        dtb_addr,
+        arch,
+    )
+}
@@ -1,5 +1,7 @@
 use crate::{arch::zone::HvArchZoneConfig, config::*};
 
+use super::StaticZone;
+
 pub const ROOT_ZONE_DTB_ADDR: u64 = 0xa0000000;
 pub const ROOT_ZONE_KERNEL_ADDR: u64 = 0xa0400000;
 pub const ROOT_ZONE_ENTRY: u64 = 0xa0400000;
@@ -8,18 +10,21 @@ pub const ROOT_ZONE_CPUS: u64 = (1 << 0) | (1 << 1);
 pub const ROOT_ZONE_MEMORY_REGIONS: [HvConfigMemoryRegion; 3] = [
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_RAM,
+        flags: 0,
         physical_start: 0x50000000,
         virtual_start: 0x50000000,
         size: 0x80000000,
     }, // ram
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_IO,
+        flags: 0,
         physical_start: 0x9000000,
         virtual_start: 0x9000000,
         size: 0x1000,
     }, // serial
     HvConfigMemoryRegion {
         mem_type: MEM_TYPE_IO,
+        flags: 0,
         physical_start: 0xa000000,
         virtual_start: 0xa000000,
         size: 0x4000,
@@ -34,3 +39,7 @@ pub const ROOT_ARCH_ZONE_CONFIG: HvArchZoneConfig = HvArchZoneConfig {
     gicr_base: 0x80a0000,
     gicr_size: 0xf60000,
 };
+
+/// Zones to partition the board into at boot, without a root zone. Empty
+/// boots the root zone above.
+pub const STATIC_ZONES: &[StaticZone] = &[];
@@ -0,0 +1,634 @@
+//! vCPU time sharing.
+//!
+//! Zones with a time slice may share physical cpus with each other. The vCPU
+//! running on a cpu lives in its `PerCpu` as usual; the others wait in the
+//! cpu's run queue with their guest context saved. The hyp timer switches to
+//! the next runnable vCPU at the end of each slice, and a vCPU that turns
+//! itself off hands the cpu over right away.
+//!
+//! Zones may instead declare windows of a cyclic schedule, repeated every
+//! major frame. A windowed zone runs only in its windows, which no other
+//! zone can take, and zones with a time slice share what is left. A zone
+//! still running well after its window has ended is reported as overrun.
+//!
+//! Interrupts for a waiting vCPU are kept with it and injected once it runs.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, RwLock};
+
+use crate::arch::timer::{
+    current_ticks, hyp_timer_cancel, hyp_timer_deadline, hyp_timer_set_deadline, ms_to_ticks,
+    ticks_to_us, HYP_TIMER_SCHED,
+};
+use crate::arch::vcpu::VcpuContext;
+use crate::consts::{INVALID_ADDRESS, MAX_CPU_NUM};
+use crate::device::irqchip::gicv3::inject_irq;
+use crate::event::{send_event, IPI_EVENT_SCHEDULE, IPI_EVENT_WAKEUP};
+use crate::hypercall::SGI_IPI_ID;
+use crate::percpu::{get_cpu_data, this_cpu_data, PerCpu};
+use crate::zone::Zone;
+
+/// A guest cpu waiting for its physical cpu.
+struct Vcpu {
+    zone: Arc<RwLock<Zone>>,
+    zone_id: usize,
+    /// The zone runs in the windows of the cyclic schedule.
+    windowed: bool,
+    cpu_on_entry: usize,
+    dtb_ipa: usize,
+    boot_cpu: bool,
+    psci_on: bool,
+    /// Start from `cpu_on_entry` instead of the saved context.
+    reset: bool,
+    paused: bool,
+    ctx: VcpuContext,
+    /// Interrupts that arrived while waiting, `(irq_id, is_hardware)`.
+    pending_irqs: Vec<(usize, bool)>,
+}
+
+impl Vcpu {
+    fn runnable(&self) -> bool {
+        self.psci_on && !self.paused
+    }
+
+    fn defer_irq(&mut self, irq_id: usize, is_hardware: bool) {
+        if !self.pending_irqs.contains(&(irq_id, is_hardware)) {
+            self.pending_irqs.push((irq_id, is_hardware));
+        }
+    }
+}
+
+type RunQueue = VecDeque<Vcpu>;
+
+const EMPTY: Mutex<RunQueue> = Mutex::new(VecDeque::new());
+static RUN_QUEUES: [Mutex<RunQueue>; MAX_CPU_NUM] = [EMPTY; MAX_CPU_NUM];
+
+const NO_WINDOW: AtomicU64 = AtomicU64::new(0);
+/// End of the window the running vCPU of each cpu was given, 0 outside
+/// windows.
+static WINDOW_END: [AtomicU64; MAX_CPU_NUM] = [NO_WINDOW; MAX_CPU_NUM];
+
+const NO_CONTEXT: Mutex<Option<VcpuContext>> = Mutex::new(None);
+/// Context of the running vCPU of each cpu while the cpu is suspended, or
+/// the context a vCPU restored from a checkpoint starts with.
+static SAVED_CONTEXTS: [Mutex<Option<VcpuContext>>; MAX_CPU_NUM] = [NO_CONTEXT; MAX_CPU_NUM];
+
+/// How late a vCPU may leave the cpu after its window before it counts as
+/// an overrun, covering the exit and switch latency.
+const WINDOW_OVERRUN_TOLERANCE_US: u64 = 50;
+
+/// Window statistics of a zone, see `HyperCallCode::HvGetSchedInfo`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvSchedInfo {
+    pub major_frame_ms: u32,
+    pub num_windows: u32,
+    /// Windows the zone has run to their end.
+    pub windows: u64,
+    /// Windows the zone kept running past their end.
+    pub overruns: u64,
+    pub max_overrun_us: u64,
+}
+
+pub struct WindowStats {
+    windows: AtomicU64,
+    overruns: AtomicU64,
+    max_overrun: AtomicU64,
+}
+
+impl WindowStats {
+    pub const fn new() -> Self {
+        Self {
+            windows: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            max_overrun: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a window left `late` ticks after its end. Returns whether it
+    /// was overrun.
+    fn record(&self, late: u64) -> bool {
+        self.windows.fetch_add(1, Ordering::Relaxed);
+        if ticks_to_us(late) <= WINDOW_OVERRUN_TOLERANCE_US {
+            return false;
+        }
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+        self.max_overrun.fetch_max(late, Ordering::Relaxed);
+        true
+    }
+
+    pub fn info(&self, major_frame_ms: u32, num_windows: u32) -> HvSchedInfo {
+        HvSchedInfo {
+            major_frame_ms,
+            num_windows,
+            windows: self.windows.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+            max_overrun_us: ticks_to_us(self.max_overrun.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+fn find_vcpu(rq: &mut RunQueue, zone_id: usize) -> Option<&mut Vcpu> {
+    rq.iter_mut().find(|vcpu| vcpu.zone_id == zone_id)
+}
+
+/// Let `cpu_id` look for a runnable vCPU.
+fn kick(cpu_id: usize) {
+    send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SCHEDULE);
+}
+
+/// Give `cpu_id` a vCPU of `zone`, turned off. It takes the cpu right away
+/// if the cpu is free or comes from the root zone, otherwise it waits behind
+/// the zones already sharing the cpu.
+pub fn add_vcpu(
+    cpu_id: usize,
+    zone: Arc<RwLock<Zone>>,
+    cpu_on_entry: usize,
+    dtb_ipa: usize,
+    boot_cpu: bool,
+) {
+    let (zone_id, windowed) = {
+        let zone = zone.read();
+        (zone.id, zone.windowed())
+    };
+    let mut rq = RUN_QUEUES[cpu_id].lock();
+    let cpu_data = get_cpu_data(cpu_id);
+    let _lock = cpu_data.ctrl_lock.lock();
+    if cpu_data
+        .zone
+        .as_ref()
+        .map_or(false, |running| running.read().id != 0)
+    {
+        rq.push_back(Vcpu {
+            zone,
+            zone_id,
+            windowed,
+            cpu_on_entry,
+            dtb_ipa,
+            boot_cpu,
+            psci_on: false,
+            reset: true,
+            paused: false,
+            ctx: VcpuContext::default(),
+            pending_irqs: Vec::new(),
+        });
+    } else {
+        cpu_data.zone = Some(zone);
+        cpu_data.cpu_on_entry = cpu_on_entry;
+        cpu_data.dtb_ipa = dtb_ipa;
+        cpu_data.boot_cpu = boot_cpu;
+    }
+}
+
+/// Find the vCPU of zone `zone_id` on `cpu_id`: `queued` gets it if it
+/// waits in the run queue, `running` gets the cpu if the vCPU runs there.
+/// Returns None if the zone has no vCPU on the cpu.
+fn with_vcpu<T>(
+    cpu_id: usize,
+    zone_id: usize,
+    queued: impl FnOnce(&mut RunQueue, usize) -> T,
+    running: impl FnOnce(&mut PerCpu) -> T,
+) -> Option<T> {
+    let mut rq = RUN_QUEUES[cpu_id].lock();
+    if let Some(idx) = rq.iter().position(|vcpu| vcpu.zone_id == zone_id) {
+        return Some(queued(&mut rq, idx));
+    }
+    // The run queue stays locked, so the cpu can't switch vCPUs meanwhile.
+    let cpu_data = get_cpu_data(cpu_id);
+    let _lock = cpu_data.ctrl_lock.lock();
+    let running_id = cpu_data.zone.as_ref().map(|zone| zone.read().id);
+    (running_id == Some(zone_id)).then(|| running(cpu_data))
+}
+
+/// Take the vCPU of zone `zone_id` off `cpu_id`. Returns true if it was
+/// running there; the cpu must then be sent `IPI_EVENT_SHUTDOWN`.
+pub fn remove_vcpu(cpu_id: usize, zone_id: usize) -> bool {
+    with_vcpu(
+        cpu_id,
+        zone_id,
+        |rq, idx| {
+            rq.remove(idx);
+            false
+        },
+        |cpu_data| {
+            cpu_data.zone = None;
+            cpu_data.cpu_on_entry = INVALID_ADDRESS;
+            true
+        },
+    )
+    .unwrap_or(false)
+}
+
+/// Turn on the vCPU of zone `zone_id` on `cpu_id` at `entry`. Returns false
+/// if it is already on.
+pub fn wake(cpu_id: usize, zone_id: usize, entry: usize) -> bool {
+    with_vcpu(
+        cpu_id,
+        zone_id,
+        |rq, idx| {
+            let vcpu = &mut rq[idx];
+            if vcpu.psci_on {
+                return false;
+            }
+            vcpu.cpu_on_entry = entry;
+            vcpu.psci_on = true;
+            vcpu.reset = true;
+            kick(cpu_id);
+            true
+        },
+        |cpu_data| {
+            if cpu_data.arch_cpu.psci_on {
+                return false;
+            }
+            cpu_data.cpu_on_entry = entry;
+            cpu_data.arch_cpu.psci_on = true;
+            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
+            true
+        },
+    )
+    .unwrap_or(false)
+}
+
+/// Restart the vCPU of zone `zone_id` on `cpu_id` from `entry`. A waiting
+/// vCPU is reset right away, and stays off unless it is the boot cpu.
+/// Returns true if the vCPU is running there; the cpu must then be sent
+/// `IPI_EVENT_REBOOT`, unless it is this cpu.
+pub fn reset_vcpu(cpu_id: usize, zone_id: usize, entry: usize) -> bool {
+    with_vcpu(
+        cpu_id,
+        zone_id,
+        |rq, idx| {
+            let vcpu = &mut rq[idx];
+            vcpu.cpu_on_entry = entry;
+            vcpu.psci_on = vcpu.boot_cpu;
+            vcpu.reset = true;
+            vcpu.paused = false;
+            vcpu.pending_irqs.clear();
+            kick(cpu_id);
+            false
+        },
+        |cpu_data| {
+            cpu_data.cpu_on_entry = entry;
+            true
+        },
+    )
+    .unwrap_or(false)
+}
+
+/// Keep the vCPU of zone `zone_id` waiting on `cpu_id` from being scheduled,
+/// or let it run again. Returns false if the zone is not waiting there.
+pub fn pause_vcpu(cpu_id: usize, zone_id: usize, paused: bool) -> bool {
+    with_vcpu(
+        cpu_id,
+        zone_id,
+        |rq, idx| {
+            rq[idx].paused = paused;
+            if !paused {
+                kick(cpu_id);
+            }
+            true
+        },
+        |_| false,
+    )
+    .unwrap_or(false)
+}
+
+/// Whether the vCPU of zone `zone_id` on `cpu_id` is on.
+pub fn is_online(cpu_id: usize, zone_id: usize) -> bool {
+    with_vcpu(
+        cpu_id,
+        zone_id,
+        |rq, idx| rq[idx].psci_on,
+        |cpu_data| cpu_data.arch_cpu.psci_on,
+    )
+    .unwrap_or(false)
+}
+
+/// Whether the vCPU of zone `zone_id` waiting on `cpu_id` is paused.
+pub fn is_paused(cpu_id: usize, zone_id: usize) -> bool {
+    find_vcpu(&mut RUN_QUEUES[cpu_id].lock(), zone_id).map_or(false, |vcpu| vcpu.paused)
+}
+
+/// State of a vCPU in a paused zone.
+pub enum VcpuState {
+    Off,
+    /// Turned on, starts from this entry once it runs.
+    Reset(usize),
+    /// Continues from the saved context.
+    Context(VcpuContext),
+}
+
+/// State of the vCPU of zone `zone_id` on `cpu_id`, which must be paused or
+/// suspended. Returns None if the zone has no vCPU there or it still runs.
+pub fn vcpu_state(cpu_id: usize, zone_id: usize) -> Option<VcpuState> {
+    with_vcpu(
+        cpu_id,
+        zone_id,
+        |rq, idx| {
+            let vcpu = &rq[idx];
+            Some(if !vcpu.psci_on {
+                VcpuState::Off
+            } else if vcpu.reset {
+                VcpuState::Reset(vcpu.cpu_on_entry)
+            } else {
+                VcpuState::Context(vcpu.ctx)
+            })
+        },
+        |cpu_data| {
+            if !cpu_data.arch_cpu.psci_on {
+                return Some(VcpuState::Off);
+            }
+            SAVED_CONTEXTS[cpu_id].lock().map(VcpuState::Context)
+        },
+    )
+    .flatten()
+}
+
+/// Turn on the vCPU of zone `zone_id` on `cpu_id` in `state`. Returns false
+/// if it is already on.
+pub fn restore_vcpu(cpu_id: usize, zone_id: usize, state: VcpuState) -> bool {
+    let ctx = match state {
+        VcpuState::Off => return true,
+        VcpuState::Reset(entry) => return wake(cpu_id, zone_id, entry),
+        VcpuState::Context(ctx) => ctx,
+    };
+    with_vcpu(
+        cpu_id,
+        zone_id,
+        |rq, idx| {
+            let vcpu = &mut rq[idx];
+            if vcpu.psci_on {
+                return false;
+            }
+            vcpu.ctx = ctx;
+            vcpu.psci_on = true;
+            vcpu.reset = false;
+            kick(cpu_id);
+            true
+        },
+        |cpu_data| {
+            if cpu_data.arch_cpu.psci_on {
+                return false;
+            }
+            *SAVED_CONTEXTS[cpu_id].lock() = Some(ctx);
+            cpu_data.arch_cpu.psci_on = true;
+            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
+            true
+        },
+    )
+    .unwrap_or(false)
+}
+
+/// Take the context a vCPU turned on by `restore_vcpu` starts with.
+pub fn take_saved_context(cpu_id: usize) -> Option<VcpuContext> {
+    SAVED_CONTEXTS[cpu_id].lock().take()
+}
+
+/// Handle `IPI_EVENT_SUSPEND`: park this cpu in EL2 with the context of its
+/// vCPU saved, where a checkpoint can read it, until it is resumed.
+pub fn suspend_current() {
+    let cpu_data = this_cpu_data();
+    let mut ctx = VcpuContext::default();
+    ctx.save(cpu_data.arch_cpu.guest_reg());
+    *SAVED_CONTEXTS[cpu_data.id].lock() = Some(ctx);
+    cpu_data.wait_for_resume();
+    if let Some(ctx) = take_saved_context(cpu_data.id) {
+        ctx.restore(cpu_data.arch_cpu.guest_reg());
+    }
+}
+
+/// What a cpu runs until the next scheduling event.
+enum Next {
+    Keep,
+    /// The waiting vCPU at this index of the run queue.
+    Switch(usize),
+    /// Nothing, the current vCPU must leave the cpu.
+    Park,
+}
+
+/// Find the window of the cyclic schedule at `now` among the zones on this
+/// cpu. Returns its zone and end, if any, and when the next window starts
+/// or ends.
+fn find_window(cpu_data: &PerCpu, rq: &RunQueue, now: u64) -> (Option<(usize, u64)>, Option<u64>) {
+    let mut window = None;
+    let mut boundary: Option<u64> = None;
+    for zone in cpu_data.zone.iter().chain(rq.iter().map(|vcpu| &vcpu.zone)) {
+        let zone = zone.read();
+        if !zone.windowed() {
+            continue;
+        }
+        // Frames start at multiples of the major frame on every cpu.
+        let frame = ms_to_ticks(zone.major_frame_ms as _);
+        let frame_start = now - now % frame;
+        for w in zone.sched_windows.iter() {
+            let start = frame_start + ms_to_ticks(w.offset_ms as _);
+            let end = start + ms_to_ticks(w.duration_ms as _);
+            let next = if now < start {
+                start
+            } else if now < end {
+                window = Some((zone.id, end));
+                end
+            } else {
+                start + frame
+            };
+            boundary = Some(boundary.map_or(next, |boundary| boundary.min(next)));
+        }
+    }
+    (window, boundary)
+}
+
+/// Move the vCPU running on this cpu, if any, to the back of the run queue.
+fn save_current(cpu_data: &mut PerCpu, rq: &mut RunQueue) {
+    if let Some(zone) = cpu_data.zone.take() {
+        let mut ctx = VcpuContext::default();
+        ctx.save(cpu_data.arch_cpu.guest_reg());
+        let (zone_id, windowed) = {
+            let zone = zone.read();
+            (zone.id, zone.windowed())
+        };
+        rq.push_back(Vcpu {
+            zone,
+            zone_id,
+            windowed,
+            cpu_on_entry: cpu_data.cpu_on_entry,
+            dtb_ipa: cpu_data.dtb_ipa,
+            boot_cpu: cpu_data.boot_cpu,
+            psci_on: cpu_data.arch_cpu.psci_on,
+            reset: false,
+            paused: false,
+            ctx,
+            pending_irqs: Vec::new(),
+        });
+    }
+}
+
+/// Let `next` take over this cpu, the guest continues with it on return.
+fn switch_to(cpu_data: &mut PerCpu, next: Vcpu) {
+    trace!("cpu {}: switch to zone {}", cpu_data.id, next.zone_id);
+    cpu_data.zone = Some(next.zone);
+    cpu_data.cpu_on_entry = next.cpu_on_entry;
+    cpu_data.dtb_ipa = next.dtb_ipa;
+    cpu_data.boot_cpu = next.boot_cpu;
+    cpu_data.arch_cpu.psci_on = true;
+
+    cpu_data.activate_gpm();
+    if next.reset {
+        cpu_data.arch_cpu.reset(next.cpu_on_entry, next.dtb_ipa);
+    } else {
+        next.ctx.restore(cpu_data.arch_cpu.guest_reg());
+    }
+    for (irq_id, is_hardware) in next.pending_irqs {
+        inject_irq(irq_id, is_hardware);
+    }
+}
+
+/// Decide what this cpu runs next and arm the hyp timer for the following
+/// decision. The owner of the current window of the cyclic schedule comes
+/// first; the time left goes round robin to the zones without windows, each
+/// for its time slice once `slice_expired`. A vCPU whose window has ended is
+/// taken off the cpu even if nothing else can run, parking the cpu.
+///
+/// Returns whether another vCPU now runs on this cpu.
+pub fn reschedule(slice_expired: bool) -> bool {
+    let cpu_data = this_cpu_data();
+    let now = current_ticks();
+    let mut rq = RUN_QUEUES[cpu_data.id].lock();
+    let (window, boundary) = find_window(cpu_data, &rq, now);
+    let owner = window.map(|(zone_id, _)| zone_id);
+
+    let current = cpu_data.zone.as_ref().map(|zone| {
+        let zone = zone.read();
+        (zone.id, zone.windowed())
+    });
+    let current_runnable = current.is_some() && cpu_data.arch_cpu.psci_on;
+    let current_slack = current_runnable && current.map_or(false, |(_, windowed)| !windowed);
+    let next = if current_runnable && current.map(|(zone_id, _)| zone_id) == owner {
+        Next::Keep
+    } else if let Some(idx) = rq
+        .iter()
+        .position(|vcpu| Some(vcpu.zone_id) == owner && vcpu.runnable())
+    {
+        Next::Switch(idx)
+    } else {
+        match rq.iter().position(|vcpu| !vcpu.windowed && vcpu.runnable()) {
+            Some(idx) if slice_expired || !current_slack => Next::Switch(idx),
+            _ if current_slack || !current_runnable => Next::Keep,
+            _ => Next::Park,
+        }
+    };
+
+    // A windowed vCPU leaving a runnable cpu had its window end.
+    let end = WINDOW_END[cpu_data.id].load(Ordering::Relaxed);
+    if !matches!(next, Next::Keep) && current_runnable && !current_slack && end != 0 {
+        let zone = cpu_data.zone.as_ref().unwrap().read();
+        if zone.window_stats.record(now.saturating_sub(end)) {
+            warn!(
+                "zone {} overran its window on cpu {} by {}us",
+                zone.id,
+                cpu_data.id,
+                ticks_to_us(now - end)
+            );
+        }
+    }
+
+    let lock = cpu_data.ctrl_lock.lock();
+    let switched = match next {
+        Next::Keep => false,
+        Next::Switch(idx) => {
+            let vcpu = rq.remove(idx).unwrap();
+            save_current(cpu_data, &mut rq);
+            switch_to(cpu_data, vcpu);
+            true
+        }
+        Next::Park => {
+            save_current(cpu_data, &mut rq);
+            cpu_data.arch_cpu.psci_on = false;
+            false
+        }
+    };
+    drop(lock);
+    drop(rq);
+
+    let running = cpu_data.zone.as_ref().filter(|_| cpu_data.arch_cpu.psci_on);
+    let in_window = running.map_or(false, |zone| Some(zone.read().id) == owner);
+    WINDOW_END[cpu_data.id].store(
+        window.filter(|_| in_window).map_or(0, |(_, end)| end),
+        Ordering::Relaxed,
+    );
+    let slice = running
+        .filter(|_| !in_window)
+        .map_or(0, |zone| zone.read().time_slice_ms);
+    let slice_end = (slice != 0).then(|| match hyp_timer_deadline(HYP_TIMER_SCHED) {
+        Some(deadline) if !switched && !slice_expired => deadline,
+        _ => now + ms_to_ticks(slice as _),
+    });
+    match (boundary, slice_end) {
+        (Some(boundary), Some(slice_end)) => {
+            hyp_timer_set_deadline(HYP_TIMER_SCHED, boundary.min(slice_end))
+        }
+        (Some(deadline), None) | (None, Some(deadline)) => {
+            hyp_timer_set_deadline(HYP_TIMER_SCHED, deadline)
+        }
+        (None, None) => hyp_timer_cancel(HYP_TIMER_SCHED),
+    }
+
+    if matches!(next, Next::Park) {
+        cpu_data.arch_cpu.park();
+    }
+    switched
+}
+
+/// Handle the hyp timer: a window or time slice has ended.
+pub fn tick() {
+    reschedule(true);
+}
+
+/// Handle `IPI_EVENT_SCHEDULE`: a waiting vCPU may have become runnable.
+pub fn schedule() {
+    reschedule(false);
+}
+
+/// Inject the hardware interrupt `irq_id` into the zone owning it, which may
+/// be waiting on this cpu. PPIs always belong to the current vCPU.
+pub fn inject_spi(irq_id: usize) {
+    let cpu_data = this_cpu_data();
+    let mut rq = RUN_QUEUES[cpu_data.id].lock();
+    let current_owns = irq_id < 32
+        || cpu_data
+            .zone
+            .as_ref()
+            .map_or(false, |zone| zone.read().irq_in_zone(irq_id as _));
+    if !current_owns {
+        if let Some(vcpu) = rq
+            .iter_mut()
+            .find(|vcpu| vcpu.zone.read().irq_in_zone(irq_id as _))
+        {
+            vcpu.defer_irq(irq_id, true);
+            return;
+        }
+    }
+    drop(rq);
+    inject_irq(irq_id, true);
+}
+
+/// Inject the virtual irq `irq_id` into zone `zone_id` on this cpu.
+pub fn inject_virq(zone_id: usize, irq_id: usize) {
+    let cpu_data = this_cpu_data();
+    if cpu_data
+        .zone
+        .as_ref()
+        .map_or(false, |zone| zone.read().id == zone_id)
+    {
+        inject_irq(irq_id, false);
+    } else if let Some(vcpu) = find_vcpu(&mut RUN_QUEUES[cpu_data.id].lock(), zone_id) {
+        vcpu.defer_irq(irq_id, false);
+    } else {
+        warn!(
+            "cpu {}: drop irq {} for zone {}",
+            cpu_data.id, irq_id, zone_id
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! VM exit statistics, counted when hvisor is built with `STATS=on`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::consts::MAX_CPU_NUM;
+use crate::percpu::this_cpu_data;
+
+const fn is_on(option: Option<&str>) -> bool {
+    match option {
+        Some(s) => {
+            let s = s.as_bytes();
+            s.len() == 2 && s[0] == b'o' && s[1] == b'n'
+        }
+        None => false,
+    }
+}
+
+pub const STATS_ENABLED: bool = is_on(option_env!("STATS"));
+
+pub const STATS_EXIT_HVC: usize = 0;
+pub const STATS_EXIT_SMC: usize = 1;
+pub const STATS_EXIT_SYSREG: usize = 2;
+pub const STATS_EXIT_DABT: usize = 3;
+pub const STATS_EXIT_IRQ: usize = 4;
+pub const STATS_EXIT_NUM: usize = 5;
+
+/// Maximum number of mmio regions reported per zone.
+pub const STATS_MAX_MMIO_REGIONS: usize = 32;
+
+/// Exit counters and time spent in EL2, in counter ticks.
+pub struct ExitStats {
+    exits: [AtomicU64; STATS_EXIT_NUM],
+    el2_ticks: AtomicU64,
+}
+
+impl ExitStats {
+    pub const fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            exits: [ZERO; STATS_EXIT_NUM],
+            el2_ticks: ZERO,
+        }
+    }
+
+    pub fn info(&self) -> HvExitStats {
+        let mut exits = [0; STATS_EXIT_NUM];
+        for (count, exit) in exits.iter_mut().zip(self.exits.iter()) {
+            *count = exit.load(Ordering::Relaxed);
+        }
+        HvExitStats {
+            exits,
+            el2_ticks: self.el2_ticks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Exit statistics reported to the root zone, indexed by `STATS_EXIT_*`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvExitStats {
+    pub exits: [u64; STATS_EXIT_NUM],
+    pub el2_ticks: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvMmioStats {
+    pub start: u64,
+    pub size: u64,
+    pub hits: u64,
+}
+
+/// Statistics of a zone, see `HyperCallCode::HvGetZoneStats`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvZoneStats {
+    pub exits: HvExitStats,
+    pub num_mmio_regions: u32,
+    pub mmio_regions: [HvMmioStats; STATS_MAX_MMIO_REGIONS],
+}
+
+static CPU_STATS: [ExitStats; MAX_CPU_NUM] = {
+    const INIT: ExitStats = ExitStats::new();
+    [INIT; MAX_CPU_NUM]
+};
+
+pub fn cpu_stats(cpu_id: usize) -> HvExitStats {
+    CPU_STATS[cpu_id].info()
+}
+
+/// Apply `f` to the statistics of the current cpu and of its zone.
+fn update(f: impl Fn(&ExitStats)) {
+    let cpu_data = this_cpu_data();
+    f(&CPU_STATS[cpu_data.id]);
+    if let Some(zone) = &cpu_data.zone {
+        f(&zone.read().stats);
+    }
+}
+
+pub fn count_exit(reason: usize) {
+    if STATS_ENABLED {
+        update(|stats| {
+            stats.exits[reason].fetch_add(1, Ordering::Relaxed);
+        });
+    }
+}
+
+pub fn count_el2_ticks(ticks: u64) {
+    if STATS_ENABLED {
+        update(|stats| {
+            stats.el2_ticks.fetch_add(ticks, Ordering::Relaxed);
+        });
+    }
+}
@@ -0,0 +1,170 @@
+//! Zone watchdogs.
+//!
+//! A zone with a watchdog must send heartbeats within its timeout. The hyp
+//! timer of the cpu that initialized the hypervisor checks the deadlines
+//! periodically while any zone has a watchdog, so recovery does not depend
+//! on the root zone being healthy. The configured action is applied to
+//! silent zones by `IPI_EVENT_WATCHDOG` on that cpu, outside of the timer
+//! interrupt handler.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once};
+
+use crate::arch::cpu::this_cpu_id;
+use crate::arch::timer::{
+    current_ticks, hyp_timer_cancel, hyp_timer_deadline, hyp_timer_set, ms_to_ticks,
+    HYP_TIMER_WATCHDOG,
+};
+use crate::config::{
+    HvWatchdogConfig, WATCHDOG_ACTION_NOTIFY_ROOT, WATCHDOG_ACTION_REBOOT, WATCHDOG_ACTION_SHUTDOWN,
+};
+use crate::error::HvResult;
+use crate::event::{send_event, send_virq, IPI_EVENT_WATCHDOG};
+use crate::hypercall::SGI_IPI_ID;
+use crate::zone::{find_zone, root_zone, zone_shutdown};
+
+/// How often the deadlines are checked.
+const WATCHDOG_PERIOD_MS: u64 = 10;
+
+struct Watchdog {
+    config: HvWatchdogConfig,
+    /// Counter value after which the zone is considered hung.
+    deadline: u64,
+}
+
+impl Watchdog {
+    fn feed(&mut self) {
+        self.deadline = current_ticks() + ms_to_ticks(self.config.timeout_ms as _);
+    }
+}
+
+static WATCHDOGS: Mutex<BTreeMap<usize, Watchdog>> = Mutex::new(BTreeMap::new());
+/// Expired watchdogs waiting for their action, `(zone_id, config)`.
+static EXPIRED: Mutex<Vec<(usize, HvWatchdogConfig)>> = Mutex::new(Vec::new());
+/// The cpu whose hyp timer checks the watchdogs.
+static WATCHDOG_CPU: Once<usize> = Once::new();
+/// Whether the hyp timer of the watchdog cpu is ready, see `init`.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Make this cpu the watchdog cpu, before the boot zones are created so that
+/// they are checked against it.
+pub fn init_early() {
+    WATCHDOG_CPU.call_once(this_cpu_id);
+}
+
+/// Check the watchdogs on this cpu, from now on while any zone has one.
+pub fn init() {
+    assert_eq!(watchdog_cpu(), Some(this_cpu_id()));
+    STARTED.store(true, Ordering::Release);
+    arm();
+}
+
+pub fn watchdog_cpu() -> Option<usize> {
+    WATCHDOG_CPU.get().copied()
+}
+
+/// Start or stop the periodic check on the watchdog cpu, depending on
+/// whether any zone has a watchdog.
+fn arm() {
+    if WATCHDOGS.lock().is_empty() {
+        hyp_timer_cancel(HYP_TIMER_WATCHDOG);
+    } else if hyp_timer_deadline(HYP_TIMER_WATCHDOG).is_none() {
+        hyp_timer_set(HYP_TIMER_WATCHDOG, WATCHDOG_PERIOD_MS);
+    }
+}
+
+/// Arm the watchdog of zone `zone_id`, if its config has one.
+pub fn register(zone_id: usize, config: &HvWatchdogConfig) {
+    if config.timeout_ms == 0 {
+        return;
+    }
+    let mut watchdog = Watchdog {
+        config: *config,
+        deadline: 0,
+    };
+    watchdog.feed();
+    let mut watchdogs = WATCHDOGS.lock();
+    let first = watchdogs.is_empty();
+    watchdogs.insert(zone_id, watchdog);
+    drop(watchdogs);
+    // Before `init`, the periodic check is started there.
+    if first && STARTED.load(Ordering::Acquire) {
+        send_event(watchdog_cpu().unwrap(), SGI_IPI_ID as _, IPI_EVENT_WATCHDOG);
+    }
+}
+
+/// Disarm the watchdog of zone `zone_id`. The periodic check stops on its
+/// next run if no watchdog is left.
+pub fn unregister(zone_id: usize) {
+    WATCHDOGS.lock().remove(&zone_id);
+}
+
+/// Push the deadline of zone `zone_id` one timeout further.
+pub fn heartbeat(zone_id: usize) -> HvResult {
+    match WATCHDOGS.lock().get_mut(&zone_id) {
+        Some(watchdog) => {
+            watchdog.feed();
+            Ok(())
+        }
+        None => hv_result_err!(ENODEV, "Zone has no watchdog"),
+    }
+}
+
+/// Handle the hyp timer interrupt: queue the zones that went silent for
+/// `IPI_EVENT_WATCHDOG`, and check again while any zone has a watchdog.
+pub fn check() {
+    let now = current_ticks();
+    let mut watchdogs = WATCHDOGS.lock();
+    let mut expired = EXPIRED.lock();
+    expired.extend(
+        watchdogs
+            .iter_mut()
+            .filter(|(_, watchdog)| watchdog.deadline <= now)
+            .map(|(&zone_id, watchdog)| {
+                // The zone gets a full timeout again after recovery.
+                watchdog.feed();
+                (zone_id, watchdog.config)
+            }),
+    );
+    if !expired.is_empty() {
+        send_event(this_cpu_id(), SGI_IPI_ID as _, IPI_EVENT_WATCHDOG);
+    }
+    if !watchdogs.is_empty() {
+        hyp_timer_set(HYP_TIMER_WATCHDOG, WATCHDOG_PERIOD_MS);
+    }
+}
+
+/// Handle `IPI_EVENT_WATCHDOG` on the watchdog cpu: recover the zones that
+/// went silent, then start or stop the periodic check.
+pub fn handle_event() {
+    let expired: Vec<_> = EXPIRED.lock().drain(..).collect();
+    for (zone_id, config) in expired {
+        let zone = match find_zone(zone_id) {
+            Some(zone) => zone,
+            None => continue,
+        };
+        if zone.read().owns_cpu(this_cpu_id()) {
+            // Recovering the zone needs this cpu to leave it.
+            warn!(
+                "zone {} watchdog: runs on the watchdog cpu, skipped",
+                zone_id
+            );
+            continue;
+        }
+        warn!("zone {} watchdog expired", zone_id);
+        match config.action {
+            WATCHDOG_ACTION_REBOOT => zone.read().reboot(),
+            WATCHDOG_ACTION_SHUTDOWN => zone_shutdown(zone),
+            WATCHDOG_ACTION_NOTIFY_ROOT => {
+                if let Some(cpu_id) = root_zone().and_then(|root| root.read().first_online_cpu()) {
+                    send_virq(cpu_id, 0, config.notify_irq as _);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    arm();
+}
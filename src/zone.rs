@@ -1,18 +1,60 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use psci::error::INVALID_ADDRESS;
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 
+use crate::arch::cache::{dcache_clean_invalidate_range, icache_invalidate_all};
+use crate::arch::cpu::this_cpu_id;
 use crate::arch::mm::new_s2_memory_set;
 use crate::arch::s2pt::Stage2PageTable;
-use crate::config::HvZoneConfig;
-use crate::consts::MAX_CPU_NUM;
+use crate::config::{
+    HvConfigMemoryRegion, HvIvshmemConfig, HvSchedWindow, HvZoneConfig, CONFIG_MAX_MEMORY_REGIONS,
+    IVSHMEM_FLAG_WRITE, MEM_FLAGS_ALL, MEM_FLAG_DEVICE, MEM_FLAG_UNCACHED, MEM_TYPE_IO,
+    MEM_TYPE_IVSHMEM, MEM_TYPE_RAM, MEM_TYPE_VIRTIO, WATCHDOG_ACTION_NOTIFY_ROOT,
+};
+use crate::consts::{hv_end, hv_start, INVALID_ADDRESS, MAX_CPU_NUM, PAGE_SIZE};
 
+use crate::device::debug_console;
+use crate::device::irqchip::overlaps_gic;
+use crate::device::ivshmem::mmio_ivshmem_handler;
+use crate::device::mailbox;
 use crate::error::HvResult;
-use crate::memory::addr::GuestPhysAddr;
-use crate::memory::{MMIOConfig, MMIOHandler, MMIORegion, MemorySet};
-use crate::percpu::{get_cpu_data, this_zone, CpuSet};
+use crate::event::{send_event, IPI_EVENT_REBOOT, IPI_EVENT_SHUTDOWN};
+use crate::hypercall::SGI_IPI_ID;
+use crate::memory::addr::{is_aligned, phys_to_virt, virt_to_phys, GuestPhysAddr};
+use crate::memory::mapper::Mapper;
+use crate::memory::{MMIOConfig, MMIOHandler, MMIORegion, MemFlags, MemoryRegion, MemorySet};
+use crate::percpu::{get_cpu_data, resume_cpu, suspend_cpu, this_zone, CpuSet};
+use crate::platform;
+use crate::sched::{self, HvSchedInfo, WindowStats};
+use crate::stats::{ExitStats, HvMmioStats, HvZoneStats, STATS_ENABLED, STATS_MAX_MMIO_REGIONS};
+use crate::wait_for;
+use crate::watchdog;
 use core::panic;
+use core::ptr::write_bytes;
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// None of the zone's cpus has been started yet, or all of them are off.
+pub const ZONE_STATE_STOPPED: u32 = 0;
+/// At least one cpu of the zone is running guest code.
+pub const ZONE_STATE_RUNNING: u32 = 1;
+/// The zone's cpus are parked in the hypervisor, see `HyperCallCode::HvZonePause`.
+pub const ZONE_STATE_PAUSED: u32 = 2;
+
+/// Zone status reported to the root zone, see `HyperCallCode::HvZoneList`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvZoneInfo {
+    pub zone_id: u32,
+    pub state: u32,
+    pub cpus: u64,
+    pub online_cpus: u64,
+    /// Number of memory regions of the zone, `memory_regions` holds the
+    /// first `CONFIG_MAX_MEMORY_REGIONS` of them.
+    pub num_memory_regions: u32,
+    pub memory_regions: [HvConfigMemoryRegion; CONFIG_MAX_MEMORY_REGIONS],
+    pub irq_bitmap: [u32; 1024 / 32],
+}
 
 pub struct Zone {
     pub id: usize,
@@ -20,6 +62,21 @@ pub struct Zone {
     pub cpu_set: CpuSet,
     pub irq_bitmap: [u32; 1024 / 32],
     pub gpm: MemorySet<Stage2PageTable>,
+    /// Entry of the boot cpu, used again when the zone is rebooted.
+    pub entry_point: usize,
+    /// Root zone memory handed over to this zone, mapped back into the root
+    /// zone when this zone is removed.
+    pub root_regions: Vec<MemoryRegion<GuestPhysAddr>>,
+    pub stats: ExitStats,
+    pub ivshmem: Vec<HvIvshmemConfig>,
+    /// Time slice of the zone's vCPUs on cpus shared with other zones.
+    pub time_slice_ms: u32,
+    pub major_frame_ms: u32,
+    /// Windows of the cyclic schedule, sorted by offset.
+    pub sched_windows: Vec<HvSchedWindow>,
+    pub window_stats: WindowStats,
+    /// The config the zone was created from, kept for checkpoints.
+    pub config: Option<HvZoneConfig>,
 }
 
 impl Zone {
@@ -30,29 +87,248 @@ impl Zone {
             cpu_set: CpuSet::new(MAX_CPU_NUM as usize, 0),
             mmio: Vec::new(),
             irq_bitmap: [0; 1024 / 32],
+            entry_point: INVALID_ADDRESS,
+            root_regions: Vec::new(),
+            stats: ExitStats::new(),
+            ivshmem: Vec::new(),
+            time_slice_ms: 0,
+            major_frame_ms: 0,
+            sched_windows: Vec::new(),
+            window_stats: WindowStats::new(),
+            config: None,
         }
     }
 
-    // pub fn suspend(&self) {
-    //     trace!("suspending cpu_set = {:#x?}", self.cpu_set);
-    //     self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
-    //         trace!("try to suspend cpu_id = {:#x?}", cpu_id);
-    //         suspend_cpu(cpu_id);
-    //     });
-    //     info!("send sgi done!");
-    // }
+    /// Park every cpu of this zone in EL2, keeping the guest state.
+    pub fn suspend(&self) {
+        suspend_zone_cpus(self.id, self.cpu_set);
+    }
 
-    // pub fn resume(&self) {
-    //     trace!("resuming cpu_set = {:#x?}", self.cpu_set);
-    //     self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
-    //         trace!("try to resume cpu_id = {:#x?}", cpu_id);
-    //         resume_cpu(cpu_id);
-    //     });
-    // }
+    /// Let the cpus parked by `suspend` continue where they stopped.
+    pub fn resume(&self) {
+        trace!("resuming cpu_set = {:#x?}", self.cpu_set);
+        self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
+            trace!("try to resume cpu_id = {:#x?}", cpu_id);
+            if !sched::pause_vcpu(cpu_id, self.id, false) {
+                resume_cpu(cpu_id);
+            }
+        });
+        info!("zone {} resumed", self.id);
+    }
 
-    // pub fn owns_cpu(&self, id: usize) -> bool {
-    //     self.cpu_set.contains_cpu(id)
-    // }
+    pub fn is_suspended(&self) -> bool {
+        self.cpu_set.iter().any(|cpu_id| {
+            get_cpu_data(cpu_id).suspend_cpu.load(Ordering::Acquire)
+                || sched::is_paused(cpu_id, self.id)
+        })
+    }
+
+    /// Reset every cpu of this zone and boot it again from the original entry.
+    /// The stage 2 mappings and mmio registrations are kept. The calling cpu,
+    /// if it belongs to the zone, must restart itself with `PerCpu::run_vm`.
+    pub fn reboot(&self) {
+        info!("rebooting zone {}", self.id);
+        // The restarted zone gets a full timeout to boot.
+        watchdog::heartbeat(self.id).ok();
+        self.arch_irqchip_reset();
+        self.cpu_set.iter().for_each(|cpu_id| {
+            // vCPUs waiting for a shared cpu are reset in place.
+            if !sched::reset_vcpu(cpu_id, self.id, self.entry_point) {
+                return;
+            }
+            resume_cpu(cpu_id);
+            if cpu_id != this_cpu_id() {
+                send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_REBOOT);
+            }
+        });
+    }
+
+    /// Whether the zone may share its cpus with other zones.
+    pub fn time_shared(&self) -> bool {
+        self.time_slice_ms != 0 || self.windowed()
+    }
+
+    /// Whether the zone runs in windows of the cyclic schedule.
+    pub fn windowed(&self) -> bool {
+        !self.sched_windows.is_empty()
+    }
+
+    pub fn sched_info(&self) -> HvSchedInfo {
+        self.window_stats
+            .info(self.major_frame_ms, self.sched_windows.len() as _)
+    }
+
+    pub fn owns_cpu(&self, id: usize) -> bool {
+        self.cpu_set.contains_cpu(id)
+    }
+
+    /// Fill the RAM of this zone with the scrub pattern and tear down its
+    /// stage 2 mappings, so nothing is left for the next owner of the memory.
+    fn scrub_memory(&mut self) {
+        let pattern = scrub_pattern();
+        for region in self.gpm.regions().filter(|region| {
+            !region
+                .flags
+                .intersects(MemFlags::IO | MemFlags::COMMUNICATION)
+                && matches!(region.mapper, Mapper::Offset(_))
+        }) {
+            let vaddr = phys_to_virt(region.mapper.map_fn(region.start));
+            unsafe { write_bytes(vaddr as *mut u8, pattern, region.size) };
+            dcache_clean_invalidate_range(vaddr, region.size);
+        }
+        self.gpm.clear();
+        info!("zone {} memory scrubbed with {:#x}", self.id, pattern);
+    }
+
+    /// Map the shared memory regions of `config` and register their doorbells.
+    fn ivshmem_init(&mut self, config: &HvZoneConfig) -> HvResult {
+        for ivshmem in config.ivshmem() {
+            let region = config
+                .memory_regions()
+                .iter()
+                .find(|region| {
+                    region.mem_type == MEM_TYPE_IVSHMEM
+                        && region.physical_start == ivshmem.physical_start
+                })
+                .unwrap();
+            let mut flags = MemFlags::READ | MemFlags::COMMUNICATION;
+            if ivshmem.flags & IVSHMEM_FLAG_WRITE != 0 {
+                flags |= MemFlags::WRITE;
+            }
+            self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                region.virtual_start as GuestPhysAddr,
+                region.physical_start as _,
+                region.size as _,
+                flags,
+            ))?;
+            self.mmio_region_register(
+                ivshmem.doorbell_base as _,
+                PAGE_SIZE,
+                mmio_ivshmem_handler,
+                ivshmem.physical_start as _,
+            );
+            self.ivshmem.push(*ivshmem);
+        }
+        Ok(())
+    }
+
+    /// Whether `paddr..paddr + size` lies in one RAM region of this zone.
+    fn contains_ram(&self, paddr: usize, size: usize) -> bool {
+        self.gpm
+            .regions()
+            .filter(|region| {
+                !region
+                    .flags
+                    .intersects(MemFlags::IO | MemFlags::COMMUNICATION)
+                    && matches!(region.mapper, Mapper::Offset(_))
+            })
+            .any(|region| {
+                let start = region.mapper.map_fn(region.start);
+                start <= paddr
+                    && paddr
+                        .checked_add(size)
+                        .map_or(false, |end| end <= start + region.size)
+            })
+    }
+
+    /// Copy the images that `config` points to from the calling zone's memory
+    /// to their load addresses in this zone.
+    fn load_images(&self, config: &HvZoneConfig) -> HvResult {
+        let images = [
+            (
+                config.kernel_image,
+                config.kernel_load_paddr,
+                config.kernel_size,
+            ),
+            (config.dtb_image, config.dtb_load_paddr, config.dtb_size),
+            (
+                config.initrd_image,
+                config.initrd_load_paddr,
+                config.initrd_size,
+            ),
+        ];
+        let caller = this_zone();
+        let caller = caller.read();
+        for (src, paddr, size) in images.into_iter().filter(|(src, _, _)| *src != 0) {
+            let (paddr, size) = (paddr as usize, size as usize);
+            if !self.contains_ram(paddr, size) {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "Image {:#x?} out of zone {} RAM",
+                        paddr..paddr + size,
+                        self.id
+                    )
+                );
+            }
+            let vaddr = phys_to_virt(paddr);
+            let dst = unsafe { slice::from_raw_parts_mut(vaddr as *mut u8, size) };
+            caller.gpm.copy_from_guest(src as _, dst)?;
+            dcache_clean_invalidate_range(vaddr, size);
+            info!(
+                "zone {}: image loaded at {:#x?}",
+                self.id,
+                paddr..paddr + size
+            );
+        }
+        icache_invalidate_all();
+        Ok(())
+    }
+
+    /// Generate the device tree of this zone at its load address, if `config`
+    /// asks for one.
+    fn write_guest_dtb(&self, config: &HvZoneConfig) -> HvResult {
+        if config.guest_dtb.is_none() {
+            return Ok(());
+        }
+        let dtb = platform::guest_dtb(config)?;
+        let (paddr, size) = (config.dtb_load_paddr as usize, dtb.len());
+        if !self.contains_ram(paddr, size) {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "Dtb {:#x?} out of zone {} RAM",
+                    paddr..paddr + size,
+                    self.id
+                )
+            );
+        }
+        let vaddr = phys_to_virt(paddr);
+        let dst = unsafe { slice::from_raw_parts_mut(vaddr as *mut u8, size) };
+        dst.copy_from_slice(&dtb);
+        dcache_clean_invalidate_range(vaddr, size);
+        info!(
+            "zone {}: dtb generated at {:#x?}",
+            self.id,
+            paddr..paddr + size
+        );
+        Ok(())
+    }
+
+    /// Unmap the host physical range `paddr..paddr + size` from this zone and
+    /// return the removed regions.
+    fn unmap_physical(
+        &mut self,
+        paddr: usize,
+        size: usize,
+    ) -> HvResult<Vec<MemoryRegion<GuestPhysAddr>>> {
+        let ranges: Vec<(GuestPhysAddr, usize)> = self
+            .gpm
+            .regions()
+            .filter(|region| matches!(region.mapper, Mapper::Offset(_)))
+            .filter_map(|region| {
+                let region_paddr = region.mapper.map_fn(region.start);
+                let start = region_paddr.max(paddr);
+                let end = (region_paddr + region.size).min(paddr + size);
+                (start < end).then(|| (region.start + (start - region_paddr), end - start))
+            })
+            .collect();
+        let mut removed = Vec::new();
+        for (start, size) in ranges {
+            removed.extend(self.gpm.unmap_partial(start, size)?);
+        }
+        Ok(removed)
+    }
 
     /// Register a mmio region and its handler.
     pub fn mmio_region_register(
@@ -74,6 +350,7 @@ impl Zone {
                 region: MMIORegion { start, size },
                 handler,
                 arg,
+                hits: AtomicU64::new(0),
             })
         }
     }
@@ -98,7 +375,12 @@ impl Zone {
         self.mmio
             .iter()
             .find(|cfg| cfg.region.contains_region(addr, size))
-            .map(|cfg| (cfg.region, cfg.handler, cfg.arg))
+            .map(|cfg| {
+                if STATS_ENABLED {
+                    cfg.hits.fetch_add(1, Ordering::Relaxed);
+                }
+                (cfg.region, cfg.handler, cfg.arg)
+            })
     }
     /// If irq_id belongs to this zone
     pub fn irq_in_zone(&self, irq_id: u32) -> bool {
@@ -106,16 +388,124 @@ impl Zone {
         let bit_pos = (irq_id % 32) as usize;
         (self.irq_bitmap[idx] & (1 << bit_pos)) != 0
     }
+
+    /// Whether the zone's vCPU on `cpu_id` is on, even if it waits for the cpu.
+    pub fn cpu_online(&self, cpu_id: usize) -> bool {
+        sched::is_online(cpu_id, self.id)
+    }
+
+    /// Bitmap of the zone's cpus that are currently running guest code.
+    pub fn online_cpus(&self) -> u64 {
+        self.cpu_set
+            .iter()
+            .filter(|&cpu_id| self.cpu_online(cpu_id))
+            .fold(0, |bitmap, cpu_id| bitmap | (1 << cpu_id))
+    }
+
+    /// The cpu to notify the zone through, if any of its cpus is running.
+    pub fn first_online_cpu(&self) -> Option<usize> {
+        self.cpu_set.iter().find(|&cpu_id| self.cpu_online(cpu_id))
+    }
+
+    /// Collect the zone's current status. Memory regions are taken from the
+    /// stage 2 mappings, so emulated (virtio) regions are not reported. The
+    /// regions that don't fit are only counted.
+    pub fn info(&self) -> HvZoneInfo {
+        let mut memory_regions = [HvConfigMemoryRegion::new_empty(); CONFIG_MAX_MEMORY_REGIONS];
+        for (info, region) in memory_regions.iter_mut().zip(self.gpm.regions()) {
+            *info = HvConfigMemoryRegion {
+                mem_type: if region.flags.contains(MemFlags::IO) {
+                    MEM_TYPE_IO
+                } else if region.flags.contains(MemFlags::COMMUNICATION) {
+                    MEM_TYPE_IVSHMEM
+                } else {
+                    MEM_TYPE_RAM
+                },
+                flags: HvConfigMemoryRegion::flags_of(region.flags),
+                physical_start: region.mapper.map_fn(region.start) as _,
+                virtual_start: region.start as _,
+                size: region.size as _,
+            };
+        }
+        let online_cpus = self.online_cpus();
+        HvZoneInfo {
+            zone_id: self.id as _,
+            state: if self.is_suspended() {
+                ZONE_STATE_PAUSED
+            } else if online_cpus != 0 {
+                ZONE_STATE_RUNNING
+            } else {
+                ZONE_STATE_STOPPED
+            },
+            cpus: self.cpu_set.bitmap,
+            online_cpus,
+            num_memory_regions: self.gpm.regions().count() as _,
+            memory_regions,
+            irq_bitmap: self.irq_bitmap,
+        }
+    }
+
+    /// Collect the exit statistics of the zone and the hits of its mmio regions.
+    pub fn stats_info(&self) -> HvZoneStats {
+        let mut mmio_regions = [HvMmioStats {
+            start: 0,
+            size: 0,
+            hits: 0,
+        }; STATS_MAX_MMIO_REGIONS];
+        let mut num_mmio_regions = 0;
+        for (info, mmio) in mmio_regions.iter_mut().zip(self.mmio.iter()) {
+            *info = HvMmioStats {
+                start: mmio.region.start as _,
+                size: mmio.region.size as _,
+                hits: mmio.hits.load(Ordering::Relaxed),
+            };
+            num_mmio_regions += 1;
+        }
+        HvZoneStats {
+            exits: self.stats.info(),
+            num_mmio_regions,
+            mmio_regions,
+        }
+    }
 }
 
 static ZONE_LIST: RwLock<Vec<Arc<RwLock<Zone>>>> = RwLock::new(vec![]);
 
-pub fn root_zone() -> Arc<RwLock<Zone>> {
-    ZONE_LIST.read().get(0).cloned().unwrap()
+/// Held by `zone_create` from the check of the config until the zone is in
+/// `ZONE_LIST`, and by `zone_add_cpu`, so that they can't take the same
+/// resources concurrently. Never waited for: the holder may be waiting for
+/// the other root cpus.
+static ZONE_CREATE_LOCK: Mutex<()> = Mutex::new(());
+
+/// The root zone, which is created first and has id 0. None if the zones
+/// were partitioned statically at boot.
+pub fn root_zone() -> Option<Arc<RwLock<Zone>>> {
+    ZONE_LIST
+        .read()
+        .first()
+        .filter(|zone| zone.read().id == 0)
+        .cloned()
 }
 
 pub fn is_this_root_zone() -> bool {
-    Arc::ptr_eq(&this_zone(), &root_zone())
+    root_zone().map_or(false, |root| Arc::ptr_eq(&this_zone(), &root))
+}
+
+/// Whether the current zone reads the hypervisor console: the root zone, or
+/// the first zone when partitioned statically at boot.
+pub fn is_this_console_zone() -> bool {
+    ZONE_LIST
+        .read()
+        .first()
+        .map_or(false, |zone| Arc::ptr_eq(&this_zone(), zone))
+}
+
+/// The zones of `zone_list` other than the root zone.
+fn non_root_zones(zone_list: &[Arc<RwLock<Zone>>]) -> &[Arc<RwLock<Zone>>] {
+    match zone_list.first() {
+        Some(first) if first.read().id == 0 => &zone_list[1..],
+        _ => zone_list,
+    }
 }
 
 /// Add zone to CELL_LIST
@@ -123,8 +513,43 @@ pub fn add_zone(zone: Arc<RwLock<Zone>>) {
     ZONE_LIST.write().push(zone);
 }
 
-/// Remove zone from ZONE_LIST
+/// Stop the cpus of a non-root zone and remove it. Must not be called on one
+/// of the zone's cpus.
+pub fn zone_shutdown(zone: Arc<RwLock<Zone>>) {
+    let zone_r = zone.read();
+    let zone_id = zone_r.id;
+
+    // // return zone's cpus to root_zone
+    zone_r.cpu_set.iter().for_each(|cpu_id| {
+        if sched::remove_vcpu(cpu_id, zone_id) {
+            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+        }
+    });
+    // Paused cpus must go on to handle the shutdown event.
+    zone_r.resume();
+
+    zone_r.arch_irqchip_reset();
+
+    drop(zone_r);
+    drop(zone);
+    remove_zone(zone_id);
+}
+
+/// Remove zone from ZONE_LIST. The cpus of the zone, except the current one,
+/// must have been told to shut down; they are waited for until parked or
+/// running the vCPU of another zone sharing them. The memory of a non-root
+/// zone is then scrubbed, and its cpus and memory are given back to the root
+/// zone.
 pub fn remove_zone(zone_id: usize) {
+    let cpu_set = find_zone(zone_id).unwrap().read().cpu_set;
+    cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
+        let cpu_data = get_cpu_data(cpu_id);
+        wait_for(|| {
+            let _lock = cpu_data.ctrl_lock.lock();
+            cpu_data.zone.is_none() && cpu_data.arch_cpu.psci_on
+        });
+    });
+
     let mut zone_list = ZONE_LIST.write();
     let (idx, _) = zone_list
         .iter()
@@ -132,7 +557,218 @@ pub fn remove_zone(zone_id: usize) {
         .find(|(_, zone)| zone.read().id == zone_id)
         .unwrap();
     let removed_zone = zone_list.remove(idx);
+    drop(zone_list);
+    debug_console::flush(zone_id);
+    mailbox::unregister(zone_id);
+    watchdog::unregister(zone_id);
     assert_eq!(Arc::strong_count(&removed_zone), 1);
+    if zone_id != 0 {
+        let mut zone = removed_zone.write();
+        zone.scrub_memory();
+        return_to_root(&mut zone);
+    }
+}
+
+/// Park the cpus `cpu_set` of zone `zone_id`, see `Zone::suspend`. The cpus
+/// are waited for, callers must not hold a lock they may need.
+fn suspend_zone_cpus(zone_id: usize, cpu_set: CpuSet) {
+    trace!("suspending cpu_set = {:#x?}", cpu_set);
+    cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
+        trace!("try to suspend cpu_id = {:#x?}", cpu_id);
+        if !sched::pause_vcpu(cpu_id, zone_id, true) {
+            suspend_cpu(cpu_id);
+        }
+    });
+    info!("zone {} suspended", zone_id);
+}
+
+/// Byte written over the RAM of removed zones, set with `SCRUB` at build time.
+fn scrub_pattern() -> u8 {
+    let pattern = option_env!("SCRUB").unwrap_or("0");
+    match pattern.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => pattern.parse(),
+    }
+    .unwrap_or(0)
+}
+
+/// Take the RAM of a new non-root zone out of the root zone. Cpus shared with
+/// the root zone must have been turned off there through PSCI.
+fn take_from_root(zone: &mut Zone, config: &HvZoneConfig, root: &Arc<RwLock<Zone>>) -> HvResult {
+    let (root_id, root_cpus) = {
+        let root_r = root.read();
+        if let Some(cpu_id) = zone
+            .cpu_set
+            .iter()
+            .find(|&cpu_id| root_r.owns_cpu(cpu_id) && get_cpu_data(cpu_id).arch_cpu.psci_on)
+        {
+            return hv_result_err!(
+                EBUSY,
+                format!("Cpu {} is still online in root zone", cpu_id)
+            );
+        }
+        (root_r.id, root_r.cpu_set)
+    };
+    // Root cpus must not touch the memory while it is remapped. They may need
+    // the root zone to get to the point where they park.
+    suspend_zone_cpus(root_id, root_cpus);
+
+    let mut root_w = root.write();
+    let mut result = Ok(());
+    for region in config
+        .memory_regions()
+        .iter()
+        .filter(|region| region.mem_type == MEM_TYPE_RAM)
+    {
+        match root_w.unmap_physical(region.physical_start as _, region.size as _) {
+            Ok(removed) => zone.root_regions.extend(removed),
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    if result.is_err() {
+        return_memory_to_root(zone, &mut root_w);
+    }
+    root_w.resume();
+    result
+}
+
+fn return_memory_to_root(zone: &mut Zone, root: &mut Zone) {
+    for region in zone.root_regions.drain(..) {
+        root.gpm.insert(region).unwrap();
+    }
+}
+
+/// Hand the cpus and memory of a removed non-root zone back to the root zone.
+/// The cpus stay off until the root zone turns them on through PSCI. Cpus
+/// still shared with other zones stay with them. Without a root zone, the
+/// cpus stay parked.
+fn return_to_root(zone: &mut Zone) {
+    let root = match root_zone() {
+        Some(root) => root,
+        None => return,
+    };
+    let zone_list = ZONE_LIST.read();
+    let others = non_root_zones(&zone_list);
+    let mut root_w = root.write();
+    return_memory_to_root(zone, &mut root_w);
+    zone.cpu_set.iter().for_each(|cpu_id| {
+        if others.iter().any(|other| other.read().owns_cpu(cpu_id)) {
+            return;
+        }
+        let cpu_data = get_cpu_data(cpu_id);
+        let _lock = cpu_data.ctrl_lock.lock();
+        root_w.cpu_set.set_bit(cpu_id);
+        cpu_data.zone = Some(root.clone());
+        cpu_data.cpu_on_entry = INVALID_ADDRESS;
+        cpu_data.boot_cpu = false;
+    });
+    info!("zone {} resources returned to root zone", zone.id);
+}
+
+/// Move the cpu `cpu_id` from zone `from` to zone `to`, which is the locked
+/// `to_zone`. The cpu must be off, it waits for a PSCI CPU_ON in `to`.
+fn move_cpu(
+    cpu_id: usize,
+    from: &mut Zone,
+    to: &mut Zone,
+    to_zone: &Arc<RwLock<Zone>>,
+) -> HvResult {
+    let cpu_data = get_cpu_data(cpu_id);
+    let _lock = cpu_data.ctrl_lock.lock();
+    if cpu_data.arch_cpu.psci_on {
+        return hv_result_err!(EBUSY, format!("Cpu {} is still online", cpu_id));
+    }
+    from.cpu_set.clear_bit(cpu_id);
+    to.cpu_set.set_bit(cpu_id);
+    cpu_data.zone = Some(to_zone.clone());
+    cpu_data.cpu_on_entry = INVALID_ADDRESS;
+    cpu_data.boot_cpu = false;
+    Ok(())
+}
+
+/// Add the cpu `cpu_id` to the running non-root zone `zone`. The cpu must be
+/// free or turned off in the root zone.
+pub fn zone_add_cpu(zone: &Arc<RwLock<Zone>>, cpu_id: usize) -> HvResult {
+    if cpu_id >= MAX_CPU_NUM {
+        return hv_result_err!(EINVAL, format!("Invalid cpu {}", cpu_id));
+    }
+    let _create = ZONE_CREATE_LOCK
+        .try_lock()
+        .ok_or(hv_err!(EBUSY, "Another zone is being created"))?;
+    if let Some(owner) = non_root_zones(&ZONE_LIST.read())
+        .iter()
+        .find(|owner| owner.read().owns_cpu(cpu_id))
+    {
+        return hv_result_err!(
+            EBUSY,
+            format!("Cpu {} is owned by zone {}", cpu_id, owner.read().id)
+        );
+    }
+
+    let mut zone_w = zone.write();
+    if zone_w.time_shared() {
+        return hv_result_err!(EINVAL, "Cpus of time-shared zones are fixed");
+    }
+    let has_watchdog = zone_w
+        .config
+        .as_ref()
+        .map_or(false, |config| config.watchdog.timeout_ms != 0);
+    if has_watchdog && watchdog::watchdog_cpu() == Some(cpu_id) {
+        return hv_result_err!(
+            EINVAL,
+            format!("Zone with a watchdog can't use the watchdog cpu {}", cpu_id)
+        );
+    }
+    let dtb_ipa = get_cpu_data(zone_w.cpu_set.first_cpu().unwrap()).dtb_ipa;
+    let root = root_zone().ok_or(hv_err!(ENODEV, "No root zone"))?;
+    move_cpu(cpu_id, &mut root.write(), &mut zone_w, zone)?;
+    get_cpu_data(cpu_id).dtb_ipa = dtb_ipa;
+    info!("cpu {} added to zone {}", cpu_id, zone_w.id);
+    Ok(())
+}
+
+/// Give the cpu `cpu_id` of the non-root zone `zone` back to the root zone.
+/// The zone must have turned the cpu off, and keeps at least one cpu.
+pub fn zone_remove_cpu(zone: &Arc<RwLock<Zone>>, cpu_id: usize) -> HvResult {
+    let mut zone_w = zone.write();
+    if zone_w.time_shared() {
+        return hv_result_err!(EINVAL, "Cpus of time-shared zones are fixed");
+    }
+    if !zone_w.owns_cpu(cpu_id) {
+        return hv_result_err!(
+            EINVAL,
+            format!("Cpu {} is not in zone {}", cpu_id, zone_w.id)
+        );
+    }
+    if zone_w.cpu_set.iter().count() == 1 {
+        return hv_result_err!(EBUSY, "Can't remove the last cpu of a zone");
+    }
+
+    let was_boot_cpu = get_cpu_data(cpu_id).boot_cpu;
+    let root = root_zone().ok_or(hv_err!(ENODEV, "No root zone"))?;
+    move_cpu(cpu_id, &mut zone_w, &mut root.write(), &root)?;
+    if was_boot_cpu {
+        get_cpu_data(zone_w.cpu_set.first_cpu().unwrap()).boot_cpu = true;
+    }
+    info!("cpu {} removed from zone {}", cpu_id, zone_w.id);
+    Ok(())
+}
+
+/// Collect the status of every zone in ZONE_LIST, in creation order.
+pub fn zone_list_info() -> Vec<HvZoneInfo> {
+    ZONE_LIST
+        .read()
+        .iter()
+        .map(|zone| zone.read().info())
+        .collect()
+}
+
+/// Number of zones in ZONE_LIST, the root zone included.
+pub fn num_zones() -> usize {
+    ZONE_LIST.read().len()
 }
 
 pub fn find_zone(zone_id: usize) -> Option<Arc<RwLock<Zone>>> {
@@ -143,6 +779,18 @@ pub fn find_zone(zone_id: usize) -> Option<Arc<RwLock<Zone>>> {
         .cloned()
 }
 
+/// Find the zone sharing the memory at `physical_start` as `peer_id`.
+/// Returns an online cpu of that zone, its id and the irq to inject into it.
+pub fn find_ivshmem_peer(physical_start: usize, peer_id: u32) -> Option<(usize, usize, u32)> {
+    ZONE_LIST.read().iter().find_map(|zone| {
+        let zone = zone.read();
+        let ivshmem = zone.ivshmem.iter().find(|ivshmem| {
+            ivshmem.physical_start as usize == physical_start && ivshmem.peer_id == peer_id
+        })?;
+        Some((zone.first_online_cpu()?, zone.id, ivshmem.irq))
+    })
+}
+
 pub fn this_zone_id() -> usize {
     this_zone().read().id
 }
@@ -160,23 +808,280 @@ pub fn this_zone_id() -> usize {
 //     pub dtb_load_paddr: u64,
 // }
 
+fn is_range_overlap(start0: usize, size0: usize, start1: usize, size1: usize) -> bool {
+    !(start0.saturating_add(size0) <= start1 || start0 >= start1.saturating_add(size1))
+}
+
+/// Check `config` against the hypervisor's own memory and the resources of
+/// the existing zones. The root zone is allowed to share its memory and cpus
+/// with the other zones, whose resources are reserved in its device tree.
+fn check_zone_config(config: &HvZoneConfig) -> HvResult {
+    config.check_counts()?;
+
+    let cpus = config.cpus();
+    if cpus.is_empty() {
+        return hv_result_err!(EINVAL, "Zone has no cpu");
+    }
+    if let Some(cpu_id) = cpus.iter().find(|&&cpu_id| cpu_id as usize >= MAX_CPU_NUM) {
+        return hv_result_err!(EINVAL, format!("Invalid cpu {}", cpu_id));
+    }
+    if let Some(irq) = config.interrupts().iter().find(|&&irq| irq >= 1024) {
+        return hv_result_err!(EINVAL, format!("Invalid irq {}", irq));
+    }
+    for ivshmem in config.ivshmem() {
+        if !config.memory_regions().iter().any(|region| {
+            region.mem_type == MEM_TYPE_IVSHMEM && region.physical_start == ivshmem.physical_start
+        }) {
+            return hv_result_err!(
+                EINVAL,
+                format!("No shared memory region for {:#x?}", ivshmem)
+            );
+        }
+        if !is_aligned(ivshmem.doorbell_base as _) || !config.interrupts().contains(&ivshmem.irq) {
+            return hv_result_err!(EINVAL, format!("Invalid ivshmem {:#x?}", ivshmem));
+        }
+    }
+    let channels = config.channels();
+    for (i, channel) in channels.iter().enumerate() {
+        if channel.name[0] == 0
+            || channels[..i].iter().any(|c| c.name == channel.name)
+            || !config.interrupts().contains(&channel.irq)
+        {
+            return hv_result_err!(EINVAL, format!("Invalid channel {:#x?}", channel));
+        }
+    }
+
+    let watchdog = &config.watchdog;
+    if watchdog.timeout_ms != 0 {
+        if watchdog.action > WATCHDOG_ACTION_NOTIFY_ROOT
+            || (watchdog.action == WATCHDOG_ACTION_NOTIFY_ROOT && watchdog.notify_irq >= 1024)
+        {
+            return hv_result_err!(EINVAL, format!("Invalid watchdog {:#x?}", watchdog));
+        }
+        // The watchdog cpu couldn't recover a zone running on it.
+        let cpu_id = watchdog::watchdog_cpu().ok_or(hv_err!(ENODEV, "No watchdog cpu"))?;
+        if cpus.contains(&(cpu_id as u64)) {
+            return hv_result_err!(
+                EINVAL,
+                format!("Zone with a watchdog can't use the watchdog cpu {}", cpu_id)
+            );
+        }
+    }
+
+    let windows = config.sched_windows();
+    if !windows.is_empty() {
+        let frame = config.major_frame_ms as u64;
+        if frame == 0
+            || windows
+                .iter()
+                .any(|w| w.duration_ms == 0 || w.offset_ms as u64 + w.duration_ms as u64 > frame)
+            || windows
+                .windows(2)
+                .any(|pair| pair[0].offset_ms + pair[0].duration_ms > pair[1].offset_ms)
+        {
+            return hv_result_err!(
+                EINVAL,
+                "Schedule windows must be sorted, disjoint and in the major frame"
+            );
+        }
+    }
+
+    // Virtio requests keep the cpu waiting in the hypervisor for the root zone.
+    let virtio = config
+        .memory_regions()
+        .iter()
+        .any(|region| region.mem_type == MEM_TYPE_VIRTIO);
+    if virtio && config.time_shared() {
+        return hv_result_err!(EINVAL, "Time-shared zones can't use virtio");
+    }
+    if virtio && config.zone_id != 0 && root_zone().is_none() {
+        return hv_result_err!(EINVAL, "Virtio needs the root zone");
+    }
+    if let Some(guest_dtb) = &config.guest_dtb {
+        let num_virtio = config
+            .memory_regions()
+            .iter()
+            .filter(|region| region.mem_type == MEM_TYPE_VIRTIO)
+            .count();
+        if guest_dtb.virtio_irqs.len() != num_virtio
+            || guest_dtb
+                .virtio_irqs
+                .iter()
+                .any(|irq| *irq < 32 || !config.interrupts().contains(irq))
+        {
+            return hv_result_err!(
+                EINVAL,
+                "Guest dtb needs an SPI of the zone for each virtio region"
+            );
+        }
+    }
+
+    let zone_list = ZONE_LIST.read();
+    for region in config.memory_regions() {
+        let (start, size) = (region.physical_start as usize, region.size as usize);
+        if size == 0
+            || start.checked_add(size).is_none()
+            || (region.virtual_start as usize).checked_add(size).is_none()
+        {
+            return hv_result_err!(EINVAL, format!("Memory region {:#x?} out of range", region));
+        }
+        // Device registers must not be mapped as normal memory.
+        if region.flags & MEM_FLAG_UNCACHED != 0
+            && (region.mem_type == MEM_TYPE_IO || region.mem_type == MEM_TYPE_VIRTIO)
+        {
+            return hv_result_err!(EINVAL, format!("Uncached IO memory region {:#x?}", region));
+        }
+        match region.mem_type {
+            MEM_TYPE_RAM | MEM_TYPE_IO | MEM_TYPE_IVSHMEM => {}
+            MEM_TYPE_VIRTIO => continue,
+            _ => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("Unsupported memory type: {}", region.mem_type)
+                )
+            }
+        }
+        if region.flags & !MEM_FLAGS_ALL != 0
+            || region.flags & (MEM_FLAG_DEVICE | MEM_FLAG_UNCACHED)
+                == MEM_FLAG_DEVICE | MEM_FLAG_UNCACHED
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!("Invalid flags of memory region {:#x?}", region)
+            );
+        }
+        if !is_aligned(start) || !is_aligned(region.virtual_start as _) || !is_aligned(size) {
+            return hv_result_err!(EINVAL, format!("Unaligned memory region {:#x?}", region));
+        }
+        if is_range_overlap(start, size, virt_to_phys(hv_start()), hv_end() - hv_start()) {
+            return hv_result_err!(
+                EINVAL,
+                format!("Memory region {:#x?} overlaps with hypervisor", region)
+            );
+        }
+        if overlaps_gic(start, size) {
+            return hv_result_err!(
+                EINVAL,
+                format!("Memory region {:#x?} overlaps with the GIC", region)
+            );
+        }
+        for zone in non_root_zones(&zone_list) {
+            let zone = zone.read();
+            if zone.gpm.regions().any(|r| {
+                let r_start = r.mapper.map_fn(r.start);
+                // Zones sharing memory map exactly the same range.
+                let shared = region.mem_type == MEM_TYPE_IVSHMEM
+                    && r.flags.contains(MemFlags::COMMUNICATION)
+                    && r_start == start
+                    && r.size == size;
+                is_range_overlap(start, size, r_start, r.size) && !shared
+            }) {
+                return hv_result_err!(
+                    EBUSY,
+                    format!(
+                        "Memory region {:#x?} overlaps with zone {}",
+                        region, zone.id
+                    )
+                );
+            }
+        }
+    }
+
+    for zone in non_root_zones(&zone_list) {
+        let zone = zone.read();
+        let cpu_id = match cpus.iter().find(|&&cpu_id| zone.owns_cpu(cpu_id as _)) {
+            Some(&cpu_id) => cpu_id,
+            None => continue,
+        };
+        if !config.time_shared() || !zone.time_shared() {
+            return hv_result_err!(
+                EBUSY,
+                format!("Cpu {} is owned by zone {}", cpu_id, zone.id)
+            );
+        }
+        // Zones with windows on the same cpu follow one cyclic schedule.
+        if !windows.is_empty() && zone.windowed() {
+            if config.major_frame_ms != zone.major_frame_ms {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("Major frame differs from zone {}", zone.id)
+                );
+            }
+            if windows.iter().any(|w| {
+                zone.sched_windows.iter().any(|other| {
+                    is_range_overlap(
+                        w.offset_ms as _,
+                        w.duration_ms as _,
+                        other.offset_ms as _,
+                        other.duration_ms as _,
+                    )
+                })
+            }) {
+                return hv_result_err!(
+                    EBUSY,
+                    format!("Schedule windows overlap with zone {}", zone.id)
+                );
+            }
+        }
+    }
+    for zone in zone_list.iter() {
+        let zone = zone.read();
+        if let Some(irq) = config
+            .interrupts()
+            .iter()
+            .find(|&&irq| zone.irq_in_zone(irq))
+        {
+            return hv_result_err!(EBUSY, format!("Irq {} is owned by zone {}", irq, zone.id));
+        }
+    }
+    Ok(())
+}
+
 pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
     // we create the new zone here
     // TODO: create Zone with cpu_set
     let zone_id = config.zone_id as usize;
 
+    let _create = ZONE_CREATE_LOCK
+        .try_lock()
+        .ok_or(hv_err!(EBUSY, "Another zone is being created"))?;
     if find_zone(zone_id).is_some() {
         return hv_result_err!(EEXIST);
     }
+    check_zone_config(config)?;
 
     let mut zone = Zone::new(zone_id);
-    zone.pt_init(config.memory_regions()).unwrap();
+    zone.entry_point = config.entry_point as _;
+    zone.time_slice_ms = config.time_slice_ms;
+    zone.major_frame_ms = config.major_frame_ms;
+    zone.sched_windows = config.sched_windows().to_vec();
+    zone.config = Some(config.clone());
+    zone.pt_init(config.memory_regions())?;
+    zone.ivshmem_init(config)?;
     zone.mmio_init(&config.arch);
     zone.irq_bitmap_init(config.interrupts());
 
     config.cpus().iter().for_each(|cpu_id| {
         zone.cpu_set.set_bit(*cpu_id as _);
     });
+    // Zones partitioned statically at boot find their images in place.
+    if let Some(root) = root_zone() {
+        take_from_root(&mut zone, config, &root)?;
+        // The images can't come from the memory just taken from the root zone.
+        if let Err(e) = zone
+            .load_images(config)
+            .and_then(|()| zone.write_guest_dtb(config))
+        {
+            return_memory_to_root(&mut zone, &mut root.write());
+            return Err(e);
+        }
+        let mut root_w = root.write();
+        zone.cpu_set
+            .iter()
+            .for_each(|cpu_id| root_w.cpu_set.clear_bit(cpu_id));
+    } else {
+        zone.write_guest_dtb(config)?;
+    }
 
     // pub struct HvConfigMemoryRegion {
     //     pub mem_type: u32,
@@ -187,10 +1092,9 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
     let mut dtb_ipa = INVALID_ADDRESS as u64;
     for region in config.memory_regions() {
         // region contains config.dtb_load_paddr?
-        if region.physical_start <= config.dtb_load_paddr
-            && region.physical_start + region.size > config.dtb_load_paddr
-        {
-            dtb_ipa = region.virtual_start + config.dtb_load_paddr - region.physical_start;
+        match config.dtb_load_paddr.checked_sub(region.physical_start) {
+            Some(offset) if offset < region.size => dtb_ipa = region.virtual_start + offset,
+            _ => {}
         }
     }
     info!("zone cpu_set: {:#b}", zone.cpu_set.bitmap);
@@ -199,16 +1103,19 @@ pub fn zone_create(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
     let new_zone_pointer = Arc::new(RwLock::new(zone));
     {
         cpu_set.iter().for_each(|cpuid| {
-            let cpu_data = get_cpu_data(cpuid);
-            cpu_data.zone = Some(new_zone_pointer.clone());
             //chose boot cpu
-            if cpuid == cpu_set.first_cpu().unwrap() {
-                cpu_data.boot_cpu = true;
-            }
-            cpu_data.cpu_on_entry = config.entry_point as _;
-            cpu_data.dtb_ipa = dtb_ipa as _;
+            let boot_cpu = cpuid == cpu_set.first_cpu().unwrap();
+            sched::add_vcpu(
+                cpuid,
+                new_zone_pointer.clone(),
+                config.entry_point as _,
+                dtb_ipa as _,
+                boot_cpu,
+            );
         });
     }
+    mailbox::register(zone_id, config.channels());
+    watchdog::register(zone_id, &config.watchdog);
     add_zone(new_zone_pointer.clone());
 
     Ok(new_zone_pointer)